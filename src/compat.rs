@@ -0,0 +1,189 @@
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
+
+/// Names Windows reserves for devices, with or without an extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// ASCII characters NTFS and FAT don't allow in names, in addition to control characters.
+const FORBIDDEN_CHARS: &str = "\"*:<>?\\|";
+
+/// Offset between printable ASCII characters and their fullwidth forms, e.g. `:` and `：`.
+const FULLWIDTH_OFFSET: u32 = 0xFF01 - 0x21;
+
+/// Offset between control characters and their Unicode control pictures, e.g. 0x01 and `␁`.
+const CONTROL_PICTURE_OFFSET: u32 = 0x2400;
+
+/// Symbol used for a trailing space, which Windows would silently drop.
+const SPACE_PICTURE: char = '\u{2420}';
+
+/// Naming rules of the filesystem TARGET is on. Names which aren't allowed there are mapped to
+/// lookalike characters, so that the same source name always ends up with the same target name
+/// and renamed files are recognized as up to date in later runs.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub(crate) enum TargetFsCompat {
+    /// Every name except `/` is allowed, names are used unchanged
+    #[default]
+    Posix,
+    /// Windows rules: no `"*:<>?\|` or control characters, no trailing dots or spaces and no
+    /// device names like `CON` or `NUL`
+    Ntfs,
+    /// FAT32 and exFAT, which share the Windows rules for long names
+    Fat,
+}
+
+fn fullwidth(c: char) -> char {
+    char::from_u32(c as u32 + FULLWIDTH_OFFSET).unwrap()
+}
+
+/// Return whether `name` is a reserved device name, ignoring case and any extension.
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name);
+    RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
+
+impl TargetFsCompat {
+    /// Map a single source name to a name which is valid on the target filesystem.
+    pub(crate) fn sanitize(&self, name: &OsStr) -> OsString {
+        if *self == TargetFsCompat::Posix {
+            return name.to_os_string();
+        }
+        // A name which isn't valid Unicode can't be stored on NTFS or FAT in any form
+        let Some(name) = name.to_str() else {
+            return name.to_os_string();
+        };
+
+        let mut chars: Vec<char> = name
+            .chars()
+            .map(|c| match c {
+                c if FORBIDDEN_CHARS.contains(c) => fullwidth(c),
+                '\u{1}'..='\u{1f}' => char::from_u32(c as u32 + CONTROL_PICTURE_OFFSET).unwrap(),
+                c => c,
+            })
+            .collect();
+        match chars.last_mut() {
+            Some(last @ '.') => *last = fullwidth('.'),
+            Some(last @ ' ') => *last = SPACE_PICTURE,
+            _ => {}
+        }
+        if is_reserved(name) {
+            // Changing the last character of the device name is enough, e.g. `CON` -> `COＮ`
+            let stem_len = name.split('.').next().unwrap().chars().count();
+            chars[stem_len - 1] = fullwidth(chars[stem_len - 1]);
+        }
+        chars.into_iter().collect::<String>().into()
+    }
+
+    /// Map every component of a path relative to SOURCE to its counterpart relative to TARGET.
+    pub(crate) fn sanitize_path(&self, path: &Path) -> PathBuf {
+        path.components()
+            .map(|component| match component {
+                Component::Normal(name) => self.sanitize(name),
+                component => component.as_os_str().to_os_string(),
+            })
+            .collect()
+    }
+
+    /// Map a name returned by `sanitize` back to the source name. A source name which already
+    /// contained one of the lookalike characters can't be told apart and is restored as well.
+    pub(crate) fn restore(&self, name: &OsStr) -> OsString {
+        let Some(name) = name.to_str().filter(|_| *self != TargetFsCompat::Posix) else {
+            return name.to_os_string();
+        };
+        let ascii = |c: char| char::from_u32(c as u32 - FULLWIDTH_OFFSET).unwrap();
+
+        let mut chars: Vec<char> = name
+            .chars()
+            .map(|c| match c {
+                c if ('\u{ff01}'..='\u{ff5e}').contains(&c)
+                    && FORBIDDEN_CHARS.contains(ascii(c)) =>
+                {
+                    ascii(c)
+                }
+                '\u{2401}'..='\u{241f}' => {
+                    char::from_u32(c as u32 - CONTROL_PICTURE_OFFSET).unwrap()
+                }
+                c => c,
+            })
+            .collect();
+        match chars.last_mut() {
+            Some(last) if *last == fullwidth('.') => *last = '.',
+            Some(last) if *last == SPACE_PICTURE => *last = ' ',
+            _ => {}
+        }
+        let stem_len = chars.iter().position(|c| *c == '.').unwrap_or(chars.len());
+        if stem_len > 0 && ('\u{ff01}'..='\u{ff5e}').contains(&chars[stem_len - 1]) {
+            let mut candidate = chars.clone();
+            candidate[stem_len - 1] = ascii(candidate[stem_len - 1]);
+            if is_reserved(&candidate.iter().collect::<String>()) {
+                chars = candidate;
+            }
+        }
+        chars.into_iter().collect::<String>().into()
+    }
+
+    /// Map every component of a path relative to TARGET back to its counterpart relative to
+    /// SOURCE.
+    pub(crate) fn restore_path(&self, path: &Path) -> PathBuf {
+        path.components()
+            .map(|component| match component {
+                Component::Normal(name) => self.restore(name),
+                component => component.as_os_str().to_os_string(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize() {
+        let cases = [
+            ("plain.txt", "plain.txt"),
+            ("a:b?.txt", "a\u{ff1a}b\u{ff1f}.txt"),
+            ("tab\there", "tab\u{2409}here"),
+            ("trailing.", "trailing\u{ff0e}"),
+            ("trailing ", "trailing\u{2420}"),
+            ("CON", "CO\u{ff2e}"),
+            ("nul.txt", "nu\u{ff4c}.txt"),
+            ("Com1.tar.gz", "Com\u{ff11}.tar.gz"),
+            ("CONSOLE", "CONSOLE"),
+        ];
+        for (name, expected) in cases {
+            let sanitized = TargetFsCompat::Ntfs.sanitize(OsStr::new(name));
+            assert_eq!(sanitized, OsStr::new(expected), "sanitizing {name:?}");
+            assert_eq!(TargetFsCompat::Ntfs.restore(&sanitized), OsStr::new(name));
+        }
+        assert_eq!(
+            TargetFsCompat::Posix.sanitize(OsStr::new("a:b")),
+            OsStr::new("a:b")
+        );
+    }
+
+    #[test]
+    fn test_sanitize_path() {
+        assert_eq!(
+            TargetFsCompat::Fat.sanitize_path(Path::new("aux/what?/x")),
+            PathBuf::from("au\u{ff58}/what\u{ff1f}/x")
+        );
+    }
+
+    #[test]
+    fn test_restore_path() {
+        let path = Path::new("aux/what?/x");
+        assert_eq!(
+            TargetFsCompat::Fat.restore_path(&TargetFsCompat::Fat.sanitize_path(path)),
+            path
+        );
+        assert_eq!(
+            TargetFsCompat::Posix.restore_path(Path::new("what\u{ff1f}")),
+            Path::new("what\u{ff1f}")
+        );
+    }
+}
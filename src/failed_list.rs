@@ -0,0 +1,234 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::file_handling::{DirectoryToCreate, Failure, FileToCopy};
+
+/// A single line of a failed list. The file is written as JSON Lines, so it can be read back
+/// by udir as well as inspected with other tools.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum FailedEntry {
+    Directory {
+        #[serde(with = "stored_path")]
+        path: PathBuf,
+        error: String,
+    },
+    File {
+        #[serde(with = "stored_path")]
+        source: PathBuf,
+        #[serde(with = "stored_path")]
+        target: PathBuf,
+        size: u64,
+        error: String,
+    },
+}
+
+/// How a path is stored in the list. JSON strings are Unicode, so a path which isn't is stored
+/// as its raw bytes on Unix or UTF-16 code units on Windows instead of being mangled.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredPath {
+    Text(String),
+    Bytes { bytes: Vec<u8> },
+    Wide { wide: Vec<u16> },
+}
+
+impl StoredPath {
+    #[cfg(unix)]
+    fn from_path(path: &Path) -> Self {
+        use std::os::unix::ffi::OsStrExt;
+
+        match path.to_str() {
+            Some(text) => StoredPath::Text(text.to_string()),
+            None => StoredPath::Bytes {
+                bytes: path.as_os_str().as_bytes().to_vec(),
+            },
+        }
+    }
+
+    #[cfg(windows)]
+    fn from_path(path: &Path) -> Self {
+        use std::os::windows::ffi::OsStrExt;
+
+        match path.to_str() {
+            Some(text) => StoredPath::Text(text.to_string()),
+            None => StoredPath::Wide {
+                wide: path.as_os_str().encode_wide().collect(),
+            },
+        }
+    }
+
+    /// Return the stored path, unless it was stored in the raw form of another platform.
+    fn into_path(self) -> Result<PathBuf, &'static str> {
+        match self {
+            StoredPath::Text(text) => Ok(PathBuf::from(text)),
+            #[cfg(unix)]
+            StoredPath::Bytes { bytes } => {
+                use std::os::unix::ffi::OsStringExt;
+
+                Ok(std::ffi::OsString::from_vec(bytes).into())
+            }
+            #[cfg(windows)]
+            StoredPath::Wide { wide } => {
+                use std::os::windows::ffi::OsStringExt;
+
+                Ok(std::ffi::OsString::from_wide(&wide).into())
+            }
+            _ => Err("path was written on another platform"),
+        }
+    }
+}
+
+mod stored_path {
+    use super::*;
+
+    pub(super) fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        StoredPath::from_path(path).serialize(serializer)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<PathBuf, D::Error> {
+        StoredPath::deserialize(deserializer)?
+            .into_path()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Write all failed directories and files with their errors to `path`. An existing file is
+/// replaced, so after a successful run the list is empty.
+pub(crate) fn write_failed_list(
+    path: &Path,
+    directories: &[Failure<DirectoryToCreate>],
+    files: &[Failure<FileToCopy>],
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let entries = directories
+        .iter()
+        .map(|failure| FailedEntry::Directory {
+            path: failure.entry.path.clone(),
+            error: failure.error.to_string(),
+        })
+        .chain(files.iter().map(|failure| FailedEntry::File {
+            source: failure.entry.source.clone(),
+            target: failure.entry.target.clone(),
+            size: failure.entry.size,
+            error: failure.error.to_string(),
+        }));
+    for entry in entries {
+        serde_json::to_writer(&mut writer, &entry)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()
+}
+
+/// Read a list written by `write_failed_list` back into the directories and files to retry.
+pub(crate) fn read_failed_list(
+    path: &Path,
+) -> io::Result<(Vec<DirectoryToCreate>, Vec<FileToCopy>)> {
+    let mut directories = Vec::new();
+    let mut files = Vec::new();
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {e}", number + 1),
+            )
+        })?;
+        match entry {
+            FailedEntry::Directory { path, .. } => directories.push(DirectoryToCreate { path }),
+            FailedEntry::File {
+                source,
+                target,
+                size,
+                ..
+            } => files.push(FileToCopy {
+                source,
+                target,
+                size,
+            }),
+        }
+    }
+    Ok((directories, files))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    #[test]
+    fn test_failed_list_round_trip() {
+        let test_dir_path = env::current_dir().unwrap().join("test_dir_failed_list");
+        let _ = fs::remove_dir_all(&test_dir_path);
+        fs::create_dir(&test_dir_path).unwrap();
+        let list_path = test_dir_path.join("failed.jsonl");
+
+        let directory = DirectoryToCreate {
+            path: PathBuf::from("/target/dir"),
+        };
+        let file = FileToCopy {
+            source: PathBuf::from("/source/a \"quoted\"\nname.txt"),
+            target: PathBuf::from("/target/a \"quoted\"\nname.txt"),
+            size: 42,
+        };
+        write_failed_list(
+            &list_path,
+            &[Failure {
+                entry: directory.clone(),
+                error: io::Error::from(io::ErrorKind::PermissionDenied),
+            }],
+            &[Failure {
+                entry: file.clone(),
+                error: io::Error::from(io::ErrorKind::TimedOut),
+            }],
+        )
+        .unwrap();
+
+        assert_eq!(fs::read_to_string(&list_path).unwrap().lines().count(), 2);
+        assert_eq!(
+            read_failed_list(&list_path).unwrap(),
+            (vec![directory], vec![file])
+        );
+
+        // A path which isn't valid Unicode is stored as it is
+        #[cfg(unix)]
+        {
+            use std::ffi::OsStr;
+            use std::os::unix::ffi::OsStrExt;
+
+            let directory = DirectoryToCreate {
+                path: Path::new("/target").join(OsStr::from_bytes(b"invalid\xff")),
+            };
+            write_failed_list(
+                &list_path,
+                &[Failure {
+                    entry: directory.clone(),
+                    error: io::Error::from(io::ErrorKind::PermissionDenied),
+                }],
+                &[],
+            )
+            .unwrap();
+            assert_eq!(
+                read_failed_list(&list_path).unwrap(),
+                (vec![directory], vec![])
+            );
+        }
+
+        fs::write(&list_path, "not json\n").unwrap();
+        assert_eq!(
+            read_failed_list(&list_path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        fs::remove_dir_all(test_dir_path).unwrap();
+    }
+}
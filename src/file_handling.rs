@@ -1,11 +1,23 @@
-use std::collections::HashSet;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::io;
 use std::path::{Path, PathBuf};
-use std::{fs, io};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::compat::TargetFsCompat;
+use crate::filter::{FileFilter, FilterKind};
+use crate::output::{Output, Verbosity};
+use crate::progress::{format_bytes, ByteProgress, ProgressReader, StatusLine};
+use crate::retry::{is_transient, RetryPolicy};
+use crate::storage::{fold_name, EntryKind, Storage};
 
 #[derive(Debug, PartialEq, Clone)]
 pub(crate) struct FileToCopy {
     pub(crate) source: PathBuf,
     pub(crate) target: PathBuf,
+    /// Size of the source file in bytes at the time of the scan
+    pub(crate) size: u64,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -13,114 +25,1113 @@ pub(crate) struct DirectoryToCreate {
     pub(crate) path: PathBuf,
 }
 
-#[derive(Debug, PartialEq)]
+/// A FIFO, socket or device node in SOURCE. Its content can't be copied, so it's either
+/// recreated on the target or skipped.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct SpecialFile {
+    pub(crate) source: PathBuf,
+    pub(crate) target: PathBuf,
+    pub(crate) kind: EntryKind,
+}
+
+/// A source file which is a hard link to an earlier scanned file, recreated as a hard link to
+/// that file's target instead of being copied.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct HardLink {
+    pub(crate) source: PathBuf,
+    pub(crate) target: PathBuf,
+    /// Target path of the first scanned name of the file
+    pub(crate) original: PathBuf,
+    /// Size of the source file in bytes, for copying it when the link can't be created
+    pub(crate) size: u64,
+}
+
+/// An entry of the plan which couldn't be applied.
+#[derive(Debug)]
+pub(crate) struct Failure<T> {
+    pub(crate) entry: T,
+    pub(crate) error: io::Error,
+}
+
+/// A path which is a directory on one side and a file on the other.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct TypeConflict {
+    pub(crate) source: PathBuf,
+    pub(crate) target: PathBuf,
+    pub(crate) source_kind: EntryKind,
+    pub(crate) target_kind: EntryKind,
+}
+
+/// A source entry whose name only differs in case or Unicode normalization from an earlier
+/// entry in the same directory, or is sanitized to the same name, so both would end up as the
+/// same entry on the target.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct NameCollision {
+    pub(crate) source: PathBuf,
+    /// Target path of the entry which keeps the name
+    pub(crate) collides_with: PathBuf,
+    /// Target path the entry is copied to instead, `None` if it's skipped
+    pub(crate) renamed_to: Option<PathBuf>,
+}
+
+/// What to do with source entries which collide on the target.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub(crate) enum NameCollisionAction {
+    /// Only copy the first entry in byte order
+    #[default]
+    Skip,
+    /// Copy the others with a ` (2)`, ` (3)`, ... suffix before the extension
+    Rename,
+}
+
+/// Order in which files are copied.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub(crate) enum CopyOrder {
+    /// By path, directory by directory
+    #[default]
+    Path,
+    /// Smallest files first
+    SizeAsc,
+    /// Largest files first
+    SizeDesc,
+    /// Most recently modified files first
+    Mtime,
+}
+
+/// Order in which `--fill` picks the files which fit into the free space of the target.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub(crate) enum FillOrder {
+    /// In the order of the plan
+    Plan,
+    /// Smallest files first, which copies as many files as possible
+    Smallest,
+    /// Largest files first
+    Largest,
+}
+
+#[derive(Debug, PartialEq, Default)]
 pub(crate) struct FilesAndDirectories {
     pub(crate) files: Vec<FileToCopy>,
     pub(crate) directories: Vec<DirectoryToCreate>,
+    pub(crate) type_conflicts: Vec<TypeConflict>,
+    /// Number of source files left out by each filter
+    pub(crate) filtered: BTreeMap<FilterKind, usize>,
+    pub(crate) name_collisions: Vec<NameCollision>,
+    /// Number of source files which were looked at, including filtered ones
+    pub(crate) scanned_files: usize,
+    /// Number of source files whose target is up to date
+    pub(crate) unchanged_files: usize,
+    /// Targets of planned files which overwrite an existing target file
+    pub(crate) updated_targets: HashSet<PathBuf>,
+    /// Special files to recreate on the target
+    pub(crate) specials: Vec<SpecialFile>,
+    /// Special files which are left out
+    pub(crate) skipped_specials: Vec<SpecialFile>,
+    /// Hard links to create once the files are copied
+    pub(crate) hard_links: Vec<HardLink>,
+}
+
+/// Settings which influence what `get_files_and_directories` puts into the plan.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct ScanOptions {
+    /// Source paths which are skipped completely, usually directories. An archive TARGET inside
+    /// SOURCE is skipped this way as well.
+    pub(crate) directories_to_skip: HashSet<PathBuf>,
+    /// Names of files and directories which are skipped wherever they appear
+    pub(crate) names_to_skip: HashSet<OsString>,
+    /// Plan entries with a type conflict as if the target didn't exist, expecting the conflicting
+    /// target entries to be removed with `resolve_type_conflicts` first. Without it, they are
+    /// only reported.
+    pub(crate) replace_type_conflicts: bool,
+    /// Only create directories which end up containing at least one copied file
+    pub(crate) prune_empty_dirs: bool,
+    /// Number of directory levels below SOURCE to descend into, `None` for no limit
+    pub(crate) max_depth: Option<usize>,
+    /// Paths relative to SOURCE and TARGET which are scanned instead of the whole tree
+    pub(crate) sub_paths: Vec<PathBuf>,
+    /// Only plan the sub-paths themselves and their parents, without descending into the
+    /// directories among them
+    pub(crate) list_only: bool,
+    /// Size and age limits for source files
+    pub(crate) filter: FileFilter,
+    /// How to handle names which collide on a case-insensitive target
+    pub(crate) name_collisions: NameCollisionAction,
+    /// Naming rules target names are adapted to
+    pub(crate) target_fs_compat: TargetFsCompat,
+    /// How much newer a source file has to be than its target to be copied again
+    pub(crate) modify_window: Duration,
+    /// Recreate FIFOs and device nodes on the target instead of skipping them. Sockets are
+    /// always skipped.
+    pub(crate) specials: bool,
+    /// Recreate files which are hard linked in SOURCE as hard links in TARGET
+    pub(crate) hard_links: bool,
+}
+
+/// Round `time` down to a multiple of `granularity`, so timestamps from backends with different
+/// precision can be compared.
+fn truncate_timestamp(time: SystemTime, granularity: Duration) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => {
+            let granularity = granularity.as_nanos().max(1);
+            let truncated = since_epoch.as_nanos() / granularity * granularity;
+            UNIX_EPOCH + Duration::from_nanos(truncated as u64)
+        }
+        Err(_) => time,
+    }
 }
 
 /// Recursively go through the source directory and its subdirectories, find all files
 /// and subdirectories, and compare whether they exist in the target directory. If not, add them
 /// to a list of files and/or directories to be copied.
-pub(crate) fn get_files_and_directories(
-    source: &PathBuf,
-    target: &PathBuf,
-    directories_to_skip: &HashSet<PathBuf>,
+pub(crate) fn get_files_and_directories<S: Storage, T: Storage>(
+    source_storage: &S,
+    target_storage: &T,
+    source: &Path,
+    target: &Path,
+    options: &ScanOptions,
 ) -> io::Result<FilesAndDirectories> {
-    let mut files = Vec::new();
-    let mut directories = Vec::new();
-
-    if source.is_dir() {
-        for entry in fs::read_dir(source)? {
-            let entry = entry?;
-            let source_path = entry.path();
-            let is_entry_dir = source_path.is_dir();
-            if is_entry_dir && !directories_to_skip.contains(&source_path) {
-                // If the source_path is a subdirectory, check whether it exists. If not, add it
-                // to be created. Call the function on the subdirectory.
-                let dir_name = source_path.file_name().unwrap();
-                let new_target = Path::new(target).join(Path::new(dir_name));
-                let dir_exists = fs::exists(&new_target)?;
-                if !dir_exists {
-                    directories.push(DirectoryToCreate {
-                        path: new_target.clone(),
-                    });
+    let mut scanner = Scanner {
+        source_storage,
+        target_storage,
+        options,
+        case_insensitive: target_storage.is_case_insensitive(target)?,
+        results: FilesAndDirectories::default(),
+        link_targets: HashMap::new(),
+        planned_directories: HashSet::new(),
+        conflicting_targets: HashSet::new(),
+    };
+
+    if source_storage.metadata(source)?.is_dir() {
+        if options.sub_paths.is_empty() {
+            scanner.scan_directory(source, target, true, 0)?;
+        } else {
+            let mut sub_paths: Vec<&PathBuf> = options.sub_paths.iter().collect();
+            sub_paths.sort();
+            sub_paths.dedup();
+            let mut scanned: Vec<&PathBuf> = Vec::new();
+            for sub_path in sub_paths {
+                // A sub-path inside one which was already scanned is covered by it
+                if !options.list_only && scanned.iter().any(|parent| sub_path.starts_with(parent)) {
+                    continue;
                 }
-                let mut result =
-                    get_files_and_directories(&source_path, &new_target, directories_to_skip)?;
-                files.append(&mut result.files);
-                directories.append(&mut result.directories);
-            } else if !is_entry_dir {
-                // Source path is a file
-                let file_name = source_path.file_name().unwrap();
-                let target_path = Path::new(target).join(Path::new(file_name));
-                let file_exists = fs::exists(&target_path)?;
-
-                if file_exists {
-                    // If the target directory contains a file with the same name as the source path,
-                    // check last modified timestamps. If the source file was modified later, re-write
-                    // the target file.
-                    let source_metadata = fs::metadata(&source_path)?;
-                    let target_metadata = fs::metadata(&target_path)?;
-
-                    let source_last_modified = source_metadata.modified()?;
-                    let target_last_modified = target_metadata.modified()?;
-
-                    if target_last_modified < source_last_modified {
-                        files.push(FileToCopy {
-                            source: source_path,
-                            target: target_path,
-                        });
+                scanner.scan_sub_path(source, target, sub_path)?;
+                scanned.push(sub_path);
+            }
+        }
+    }
+    Ok(scanner.results)
+}
+
+/// Build a name for `name` by adding ` (2)`, ` (3)`, ... before the extension until it collides
+/// with neither a source name nor a name already used in the target directory.
+/// Names are compared by their `key`.
+fn unique_name(
+    name: &OsStr,
+    source_names: &HashSet<String>,
+    target_names: &HashMap<String, PathBuf>,
+    key: impl Fn(&OsStr) -> String,
+) -> String {
+    let path = Path::new(name);
+    let stem = path.file_stem().unwrap_or(name).to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (2..)
+        .map(|number| format!("{stem} ({number}){extension}"))
+        .find(|candidate| {
+            let key = key(OsStr::new(candidate));
+            !source_names.contains(&key) && !target_names.contains_key(&key)
+        })
+        .unwrap()
+}
+
+/// State of a single `get_files_and_directories` run.
+struct Scanner<'a, S, T> {
+    source_storage: &'a S,
+    target_storage: &'a T,
+    options: &'a ScanOptions,
+    /// Whether names in the target only differing in case collide
+    case_insensitive: bool,
+    results: FilesAndDirectories,
+    /// Target path of the first scanned name of every hard linked source file
+    link_targets: HashMap<(u64, u64), PathBuf>,
+    /// Paths of `results.directories`, to plan every directory only once
+    planned_directories: HashSet<PathBuf>,
+    /// Target paths of `results.type_conflicts`, to report every conflict only once
+    conflicting_targets: HashSet<PathBuf>,
+}
+
+impl<S: Storage, T: Storage> Scanner<'_, S, T> {
+    /// Scan the entry at `sub_path` relative to `source` and `target`. Missing parent directories
+    /// in the target are added to the plan as well.
+    fn scan_sub_path(&mut self, source: &Path, target: &Path, sub_path: &Path) -> io::Result<()> {
+        let files_before = self.results.files.len();
+        let directories_before = self.results.directories.len();
+        let type_conflicts_before = self.results.type_conflicts.len();
+        let hard_links_before = self.results.hard_links.len();
+        let specials_before = self.results.specials.len();
+
+        let mut source_parent = source.to_path_buf();
+        let mut target_parent = target.to_path_buf();
+        let mut target_exists = true;
+        let mut depth = 0;
+        let components: Vec<_> = sub_path.components().collect();
+        let Some((entry_name, parents)) = components.split_last() else {
+            // An empty sub-path stands for SOURCE itself
+            return self.scan_directory(source, target, true, 0);
+        };
+        // Listed paths below a skipped directory are skipped as well
+        let mut ancestor = source.to_path_buf();
+        for component in &components {
+            ancestor.push(component);
+            if self.is_skipped(&ancestor) {
+                return Ok(());
+            }
+        }
+        let (entry_to_scan, parents_to_walk) = if self.options.list_only
+            && self
+                .source_storage
+                .metadata(&source.join(sub_path))?
+                .is_dir()
+        {
+            // Without descending, a directory is only created, exactly like a missing parent
+            (None, &components[..])
+        } else {
+            (Some(entry_name), parents)
+        };
+
+        for component in parents_to_walk {
+            source_parent.push(component);
+            target_parent.push(
+                self.options
+                    .target_fs_compat
+                    .sanitize(component.as_os_str()),
+            );
+            depth += 1;
+            if !target_exists {
+                self.push_directory(&target_parent);
+                continue;
+            }
+            match self.target_storage.entry_kind(&target_parent)? {
+                Some(EntryKind::Directory) => {}
+                Some(target_kind) => {
+                    // Several sub-paths can share the same conflicting parent
+                    self.push_type_conflict(TypeConflict {
+                        source: source_parent.clone(),
+                        target: target_parent.clone(),
+                        source_kind: EntryKind::Directory,
+                        target_kind,
+                    });
+                    if !self.options.replace_type_conflicts {
+                        return Ok(());
                     }
+                    target_exists = false;
+                    self.push_directory(&target_parent);
+                }
+                None => {
+                    target_exists = false;
+                    self.push_directory(&target_parent);
+                }
+            }
+        }
+
+        if let Some(entry_name) = entry_to_scan {
+            let target_name = self
+                .options
+                .target_fs_compat
+                .sanitize(entry_name.as_os_str());
+            self.scan_entry(
+                source_parent.join(entry_name),
+                target_parent.join(target_name),
+                target_exists,
+                depth,
+            )?;
+        }
+
+        if !target_exists
+            && self.options.prune_empty_dirs
+            && self.results.files.len() == files_before
+            && self.results.hard_links.len() == hard_links_before
+            && self.results.specials.len() == specials_before
+        {
+            self.truncate_plan(directories_before, type_conflicts_before);
+        }
+        Ok(())
+    }
+
+    /// Add a directory to be created unless it's already planned, which happens when several
+    /// sub-paths share a missing parent.
+    fn push_directory(&mut self, path: &Path) {
+        if self.planned_directories.insert(path.to_path_buf()) {
+            self.results.directories.push(DirectoryToCreate {
+                path: path.to_path_buf(),
+            });
+        }
+    }
+
+    /// Add a type conflict unless its target is already reported.
+    fn push_type_conflict(&mut self, conflict: TypeConflict) {
+        if self.conflicting_targets.insert(conflict.target.clone()) {
+            self.results.type_conflicts.push(conflict);
+        }
+    }
+
+    /// Drop the directories and type conflicts planned after the first `directories_len` and
+    /// `type_conflicts_len` ones.
+    fn truncate_plan(&mut self, directories_len: usize, type_conflicts_len: usize) {
+        for directory in self.results.directories.drain(directories_len..) {
+            self.planned_directories.remove(&directory.path);
+        }
+        for conflict in self.results.type_conflicts.drain(type_conflicts_len..) {
+            self.conflicting_targets.remove(&conflict.target);
+        }
+    }
+
+    /// Whether `source_path` is excluded by `directories_to_skip` or `names_to_skip`.
+    fn is_skipped(&self, source_path: &Path) -> bool {
+        self.options.directories_to_skip.contains(source_path)
+            || source_path
+                .file_name()
+                .is_some_and(|name| self.options.names_to_skip.contains(name))
+    }
+
+    /// Name the entry at `source_path` gets in the target.
+    fn target_name(&self, source_path: &Path) -> OsString {
+        self.options
+            .target_fs_compat
+            .sanitize(source_path.file_name().unwrap())
+    }
+
+    /// Scan a single source directory and recurse into its subdirectories. `target_exists` is
+    /// false when the target directory is going to be created, so there is nothing to compare
+    /// against. `depth` is the number of directories between SOURCE and `source`.
+    fn scan_directory(
+        &mut self,
+        source: &Path,
+        target: &Path,
+        target_exists: bool,
+        depth: usize,
+    ) -> io::Result<()> {
+        // The order of `list` depends on the filesystem, sorting makes the plan the same on
+        // every run and machine
+        let mut source_paths = self.source_storage.list(source)?;
+        source_paths.sort();
+        if !self.case_insensitive && self.options.target_fs_compat == TargetFsCompat::Posix {
+            for source_path in source_paths {
+                let target_path = target.join(self.target_name(&source_path));
+                self.scan_entry(source_path, target_path, target_exists, depth)?;
+            }
+            return Ok(());
+        }
+
+        // Names collide when they're the same on a case-insensitive target or after sanitizing.
+        // The first entry in byte order keeps its name.
+        let case_insensitive = self.case_insensitive;
+        let key = |name: &OsStr| {
+            if case_insensitive {
+                fold_name(name)
+            } else {
+                name.to_string_lossy().into_owned()
+            }
+        };
+        let source_names: HashSet<String> = source_paths
+            .iter()
+            .map(|source_path| key(&self.target_name(source_path)))
+            .collect();
+        let mut target_names: HashMap<String, PathBuf> = HashMap::new();
+        for source_path in source_paths {
+            let entry_name = self.target_name(&source_path);
+            let mut target_path = target.join(&entry_name);
+            if let Some(collides_with) = target_names.get(&key(&entry_name)) {
+                let renamed_to = match self.options.name_collisions {
+                    NameCollisionAction::Skip => None,
+                    NameCollisionAction::Rename => Some(target.join(unique_name(
+                        &entry_name,
+                        &source_names,
+                        &target_names,
+                        key,
+                    ))),
+                };
+                self.results.name_collisions.push(NameCollision {
+                    source: source_path.clone(),
+                    collides_with: collides_with.clone(),
+                    renamed_to: renamed_to.clone(),
+                });
+                match renamed_to {
+                    Some(renamed_to) => target_path = renamed_to,
+                    None => continue,
+                }
+            }
+            target_names.insert(key(target_path.file_name().unwrap()), target_path.clone());
+            self.scan_entry(source_path, target_path, target_exists, depth)?;
+        }
+        Ok(())
+    }
+
+    /// Plan `link`, a later name of a hard linked source file, unless its target is already
+    /// linked to the target of the first name. `target_kind` is the kind of entry at its target.
+    fn scan_hard_link(&mut self, link: HardLink, target_kind: Option<EntryKind>) -> io::Result<()> {
+        match target_kind {
+            Some(EntryKind::File) => {
+                let linked = self
+                    .target_storage
+                    .metadata(&link.target)?
+                    .link_id
+                    .is_some_and(|link_id| {
+                        self.target_storage
+                            .metadata(&link.original)
+                            .is_ok_and(|original| original.link_id == Some(link_id))
+                    });
+                if linked {
+                    self.results.unchanged_files += 1;
                 } else {
-                    // If the target path doesn't exist, copy the source path.
-                    files.push(FileToCopy {
+                    // A separate copy from an earlier run is replaced by the link
+                    self.results.hard_links.push(link);
+                }
+            }
+            Some(target_kind) => {
+                self.push_type_conflict(TypeConflict {
+                    source: link.source.clone(),
+                    target: link.target.clone(),
+                    source_kind: EntryKind::File,
+                    target_kind,
+                });
+                if self.options.replace_type_conflicts {
+                    self.results.hard_links.push(link);
+                }
+            }
+            None => self.results.hard_links.push(link),
+        }
+        Ok(())
+    }
+
+    /// Compare a single source entry with its counterpart in the target and add it to the plan
+    /// if needed. `target_exists` and `depth` describe the directory containing the entry.
+    fn scan_entry(
+        &mut self,
+        source_path: PathBuf,
+        target_path: PathBuf,
+        target_exists: bool,
+        depth: usize,
+    ) -> io::Result<()> {
+        if self.is_skipped(&source_path) {
+            return Ok(());
+        }
+        let source_metadata = self.source_storage.metadata(&source_path)?;
+        let is_entry_dir = source_metadata.is_dir();
+        let target_kind = if target_exists {
+            self.target_storage.entry_kind(&target_path)?
+        } else {
+            None
+        };
+
+        if is_entry_dir
+            && self
+                .options
+                .max_depth
+                .is_none_or(|max_depth| depth < max_depth)
+        {
+            // If the source_path is a subdirectory, check whether it exists. If not, add it
+            // to be created. Scan the subdirectory as well.
+            let files_before = self.results.files.len();
+            let directories_before = self.results.directories.len();
+            let type_conflicts_before = self.results.type_conflicts.len();
+            let specials_before = self.results.specials.len();
+            let hard_links_before = self.results.hard_links.len();
+            let dir_exists = match target_kind {
+                Some(EntryKind::Directory) => true,
+                Some(target_kind) => {
+                    self.push_type_conflict(TypeConflict {
+                        source: source_path.clone(),
+                        target: target_path.clone(),
+                        source_kind: EntryKind::Directory,
+                        target_kind,
+                    });
+                    if !self.options.replace_type_conflicts {
+                        return Ok(());
+                    }
+                    false
+                }
+                None => false,
+            };
+            if !dir_exists {
+                self.push_directory(&target_path);
+            }
+            self.scan_directory(&source_path, &target_path, dir_exists, depth + 1)?;
+            if !dir_exists
+                && self.options.prune_empty_dirs
+                && self.results.files.len() == files_before
+                && self.results.specials.len() == specials_before
+                && self.results.hard_links.len() == hard_links_before
+            {
+                // Nothing will be copied into the new directory, so it isn't needed at all
+                self.truncate_plan(directories_before, type_conflicts_before);
+            }
+        } else if source_metadata.kind.is_special() {
+            let special = SpecialFile {
+                source: source_path,
+                target: target_path,
+                kind: source_metadata.kind,
+            };
+            if !self.options.specials || special.kind == EntryKind::Socket {
+                self.results.skipped_specials.push(special);
+                return Ok(());
+            }
+            let create = match target_kind {
+                Some(target_kind) if target_kind == special.kind => false,
+                Some(target_kind) => {
+                    self.push_type_conflict(TypeConflict {
+                        source: special.source.clone(),
+                        target: special.target.clone(),
+                        source_kind: special.kind,
+                        target_kind,
+                    });
+                    self.options.replace_type_conflicts
+                }
+                None => true,
+            };
+            if create {
+                self.results.specials.push(special);
+            }
+        } else if !is_entry_dir {
+            // Source path is a file
+            self.results.scanned_files += 1;
+            if let Some(filter) = self.options.filter.check(&source_metadata) {
+                *self.results.filtered.entry(filter).or_default() += 1;
+                return Ok(());
+            }
+            if let Some(link_id) = source_metadata.link_id.filter(|_| self.options.hard_links) {
+                if let Some(original) = self.link_targets.get(&link_id) {
+                    let link = HardLink {
                         source: source_path,
                         target: target_path,
+                        original: original.clone(),
+                        size: source_metadata.len,
+                    };
+                    return self.scan_hard_link(link, target_kind);
+                }
+            }
+            let copy = match target_kind {
+                Some(EntryKind::File) => {
+                    // If the target directory contains a file with the same name as the source
+                    // path, check last modified timestamps. If the source file was modified
+                    // later, re-write the target file.
+                    let granularity = self
+                        .source_storage
+                        .timestamp_granularity()
+                        .max(self.target_storage.timestamp_granularity());
+                    let source_last_modified =
+                        truncate_timestamp(source_metadata.modified, granularity);
+                    let target_last_modified = truncate_timestamp(
+                        self.target_storage.metadata(&target_path)?.modified,
+                        granularity,
+                    );
+                    // Timestamps within the modify window count as equal
+                    let newer = source_last_modified
+                        .duration_since(target_last_modified)
+                        .is_ok_and(|newer_by| newer_by > self.options.modify_window);
+                    if newer {
+                        self.results.updated_targets.insert(target_path.clone());
+                    } else {
+                        self.results.unchanged_files += 1;
+                    }
+                    newer
+                }
+                Some(target_kind) => {
+                    self.push_type_conflict(TypeConflict {
+                        source: source_path.clone(),
+                        target: target_path.clone(),
+                        source_kind: EntryKind::File,
+                        target_kind,
                     });
+                    self.options.replace_type_conflicts
+                }
+                // If the target path doesn't exist, copy the source path.
+                None => true,
+            };
+            // Later names are linked to this one only if it's going to be there, whether it's up
+            // to date or copied. Whether it's really copied is up to the copy stage.
+            if let Some(link_id) = source_metadata.link_id.filter(|_| self.options.hard_links) {
+                if copy || target_kind == Some(EntryKind::File) {
+                    self.link_targets.insert(link_id, target_path.clone());
                 }
             }
+            if copy {
+                self.results.files.push(FileToCopy {
+                    source: source_path,
+                    target: target_path,
+                    size: source_metadata.len,
+                });
+            }
         }
+        Ok(())
+    }
+}
+
+/// Sort the files of a plan into the order they are copied in. Files which compare equal stay
+/// in path order.
+pub(crate) fn sort_files<S: Storage>(
+    source_storage: &S,
+    files: &mut [FileToCopy],
+    order: CopyOrder,
+) {
+    match order {
+        CopyOrder::Path => {}
+        CopyOrder::SizeAsc => files.sort_by_key(|file| file.size),
+        CopyOrder::SizeDesc => files.sort_by_key(|file| Reverse(file.size)),
+        CopyOrder::Mtime => files.sort_by_cached_key(|file| {
+            Reverse(
+                source_storage
+                    .metadata(&file.source)
+                    .map_or(UNIX_EPOCH, |metadata| metadata.modified),
+            )
+        }),
     }
-    Ok(FilesAndDirectories { files, directories })
+}
+
+/// Return how many bytes each file adds to the target, which is its size minus the size of the
+/// file it replaces.
+pub(crate) fn space_needed<T: Storage>(target_storage: &T, files: &[FileToCopy]) -> Vec<u64> {
+    files
+        .iter()
+        .map(|file| {
+            let replaced = match target_storage.metadata(&file.target) {
+                Ok(metadata) if !metadata.is_dir() => metadata.len,
+                _ => 0,
+            };
+            file.size.saturating_sub(replaced)
+        })
+        .collect()
+}
+
+/// Split `files` into the ones which fit into `available` bytes when picked in `order` and the
+/// ones which don't. Both keep the order of the plan.
+pub(crate) fn select_files_that_fit(
+    files: Vec<FileToCopy>,
+    space_needed: &[u64],
+    available: u64,
+    order: FillOrder,
+) -> (Vec<FileToCopy>, Vec<FileToCopy>) {
+    let mut indices: Vec<usize> = (0..files.len()).collect();
+    match order {
+        FillOrder::Plan => {}
+        FillOrder::Smallest => indices.sort_by_key(|&index| files[index].size),
+        FillOrder::Largest => indices.sort_by_key(|&index| Reverse(files[index].size)),
+    }
+
+    // A file which doesn't fit anymore doesn't stop smaller ones after it from being picked
+    let mut fits = vec![false; files.len()];
+    let mut remaining = available;
+    for index in indices {
+        if space_needed[index] <= remaining {
+            remaining -= space_needed[index];
+            fits[index] = true;
+        }
+    }
+
+    let (fitting, not_fitting): (Vec<_>, Vec<_>) =
+        files.into_iter().zip(fits).partition(|(_, fits)| *fits);
+    (
+        fitting.into_iter().map(|(file, _)| file).collect(),
+        not_fitting.into_iter().map(|(file, _)| file).collect(),
+    )
+}
+
+/// Remove the target entries of the provided type conflicts, so the source entries can take
+/// their place. Returns the conflicts which couldn't be resolved.
+pub(crate) fn resolve_type_conflicts<T: Storage>(
+    target_storage: &T,
+    type_conflicts: &[TypeConflict],
+    output: &Output,
+) -> Vec<TypeConflict> {
+    let mut failed_conflicts = Vec::new();
+
+    for conflict in type_conflicts {
+        let result = match conflict.target_kind {
+            EntryKind::Directory => target_storage.remove_dir_all(&conflict.target),
+            _ => target_storage.remove_file(&conflict.target),
+        };
+        match result {
+            Ok(_) => {
+                if output.shows(Verbosity::Normal) {
+                    println!(
+                        "Removed conflicting {}: {}",
+                        conflict.target_kind,
+                        conflict.target.display()
+                    );
+                }
+            }
+            Err(e) => {
+                if output.shows(Verbosity::Verbose) {
+                    println!(
+                        "Failed to remove {} {}: {e}",
+                        conflict.target_kind,
+                        conflict.target.display()
+                    );
+                }
+                failed_conflicts.push(conflict.clone());
+            }
+        }
+    }
+    failed_conflicts
+}
+
+/// Remove all empty directories below `target`, including directories which only become empty
+/// because their empty subdirectories are removed. `target` itself and every directory `keep`
+/// returns true for are left alone. Returns the directories which couldn't be removed.
+pub(crate) fn delete_empty_directories<T: Storage>(
+    target_storage: &T,
+    target: &Path,
+    keep: &impl Fn(&Path) -> bool,
+    output: &Output,
+) -> Vec<PathBuf> {
+    let mut failed_directories = Vec::new();
+    if let Err(e) = delete_empty_subdirectories(
+        target_storage,
+        target,
+        keep,
+        output,
+        &mut failed_directories,
+    ) {
+        if output.shows(Verbosity::Verbose) {
+            println!("Failed to read directory {}: {e}", target.display());
+        }
+        failed_directories.push(target.to_path_buf());
+    }
+    failed_directories
+}
+
+/// Remove the empty subdirectories of `directory` and return whether it's empty afterwards.
+fn delete_empty_subdirectories<T: Storage>(
+    target_storage: &T,
+    directory: &Path,
+    keep: &impl Fn(&Path) -> bool,
+    output: &Output,
+    failed_directories: &mut Vec<PathBuf>,
+) -> io::Result<bool> {
+    let mut is_empty = true;
+    for path in target_storage.list(directory)? {
+        // Symlinks are never followed, the directory they point to may be anywhere
+        if !target_storage
+            .metadata(&path)
+            .is_ok_and(|metadata| metadata.is_dir())
+            || target_storage.is_symlink(&path).unwrap_or(true)
+            || keep(&path)
+        {
+            is_empty = false;
+            continue;
+        }
+        let removable = match delete_empty_subdirectories(
+            target_storage,
+            &path,
+            keep,
+            output,
+            failed_directories,
+        ) {
+            Ok(removable) => removable,
+            Err(e) => {
+                if output.shows(Verbosity::Verbose) {
+                    println!("Failed to read directory {}: {e}", path.display());
+                }
+                failed_directories.push(path.clone());
+                false
+            }
+        };
+        if !removable {
+            is_empty = false;
+            continue;
+        }
+        match target_storage.remove_dir(&path) {
+            Ok(_) => {
+                if output.shows(Verbosity::Normal) {
+                    println!("Empty directory removed: {}", path.display());
+                }
+            }
+            Err(e) => {
+                if output.shows(Verbosity::Verbose) {
+                    println!("Failed to remove directory {}: {e}", path.display());
+                }
+                failed_directories.push(path);
+                is_empty = false;
+            }
+        }
+    }
+    Ok(is_empty)
+}
+
+/// Remove the source of every copied file, but only if its target still matches it in size and
+/// modification time, so a source which changed during the copy or a copy which went missing is
+/// never lost. Timestamps within `modify_window` of each other count as equal, as in the scan.
+/// Returns the removed source paths and the files whose source was kept.
+pub(crate) fn remove_source_files<S: Storage, T: Storage>(
+    source_storage: &S,
+    target_storage: &T,
+    files: Vec<FileToCopy>,
+    modify_window: Duration,
+    output: &Output,
+) -> (Vec<PathBuf>, Vec<Failure<FileToCopy>>) {
+    let granularity = source_storage
+        .timestamp_granularity()
+        .max(target_storage.timestamp_granularity());
+    let mut removed = Vec::new();
+    let mut kept = Vec::new();
+    for file in files {
+        let verified = source_storage
+            .metadata(&file.source)
+            .and_then(|source_metadata| {
+                let target_metadata = target_storage.metadata(&file.target)?;
+                let source_modified = truncate_timestamp(source_metadata.modified, granularity);
+                let target_modified = truncate_timestamp(target_metadata.modified, granularity);
+                // The target may be rounded up or down, e.g. to two seconds on FAT
+                let difference = source_modified
+                    .duration_since(target_modified)
+                    .or_else(|_| target_modified.duration_since(source_modified))
+                    .unwrap_or_default();
+                if source_metadata.len != target_metadata.len || difference > modify_window {
+                    return Err(io::Error::other("the copy doesn't match the source"));
+                }
+                Ok(())
+            });
+        match verified.and_then(|_| source_storage.remove_file(&file.source)) {
+            Ok(_) => {
+                if output.shows(Verbosity::Verbose) {
+                    println!("Source file removed: {}", file.source.display());
+                }
+                removed.push(file.source);
+            }
+            Err(error) => kept.push(Failure { entry: file, error }),
+        }
+    }
+    (removed, kept)
+}
+
+/// Remove the directories of `removed_files` below `source` which are empty now, together with
+/// their parents which become empty. Returns the directories which couldn't be removed.
+pub(crate) fn remove_empty_source_directories<S: Storage>(
+    source_storage: &S,
+    source: &Path,
+    removed_files: &[PathBuf],
+    output: &Output,
+) -> Vec<PathBuf> {
+    let mut directories: Vec<_> = removed_files
+        .iter()
+        .flat_map(|path| path.ancestors().skip(1))
+        .filter(|directory| directory.starts_with(source) && *directory != source)
+        .collect();
+    // Deeper directories first, so their parents can become empty
+    directories
+        .sort_unstable_by_key(|directory| (Reverse(directory.components().count()), *directory));
+    directories.dedup();
+    let mut failed_directories = Vec::new();
+    for directory in directories {
+        if !source_storage
+            .list(directory)
+            .is_ok_and(|entries| entries.is_empty())
+        {
+            continue;
+        }
+        match source_storage.remove_dir(directory) {
+            Ok(_) => {
+                if output.shows(Verbosity::Verbose) {
+                    println!("Empty source directory removed: {}", directory.display());
+                }
+            }
+            Err(e) => {
+                if output.shows(Verbosity::Verbose) {
+                    println!("Failed to remove directory {}: {e}", directory.display());
+                }
+                failed_directories.push(directory.to_path_buf());
+            }
+        }
+    }
+    failed_directories
 }
 
 /// Create directories from the provided slice of DirectoryToCreate structs
-pub(crate) fn create_directories(
+pub(crate) fn create_directories<T: Storage>(
+    target_storage: &T,
     list_of_directories: &[DirectoryToCreate],
-) -> Vec<DirectoryToCreate> {
+    output: &Output,
+) -> Vec<Failure<DirectoryToCreate>> {
     let len_directories = list_of_directories.len();
 
     if len_directories == 0 {
         return Vec::new();
     }
 
-    let mut failed_directories: Vec<DirectoryToCreate> = vec![];
+    let mut failed_directories = Vec::new();
+    let mut status_line = StatusLine::new(output.live_progress);
 
     for (i, directory) in list_of_directories.iter().enumerate() {
-        print!(
-            "\rCreating directories: {:.2}% ({}/{})",
-            i as f64 / len_directories as f64 * 100.,
-            i,
-            len_directories
-        );
-        // Make sure it flushes immediately
-        io::Write::flush(&mut io::stdout()).unwrap();
-        match fs::create_dir(&directory.path) {
-            Ok(_) => println!("\rDirectory created: {}", directory.path.display()),
-            Err(_) => failed_directories.push(directory.clone()),
+        if status_line.is_due(false) {
+            status_line.draw(&format!(
+                "Creating directories: {:.2}% ({}/{})",
+                i as f64 / len_directories as f64 * 100.,
+                i,
+                len_directories
+            ));
+        }
+        match target_storage.create_dir(&directory.path) {
+            Ok(_) => {
+                if output.shows(Verbosity::Normal) {
+                    status_line
+                        .println(&format!("Directory created: {}", directory.path.display()));
+                }
+            }
+            Err(e) => {
+                if output.shows(Verbosity::Verbose) {
+                    status_line.println(&format!(
+                        "Failed to create directory {}: {e}",
+                        directory.path.display()
+                    ));
+                }
+                failed_directories.push(Failure {
+                    entry: directory.clone(),
+                    error: e,
+                });
+            }
         }
     }
 
-    println!(
-        "\rCreating directories: 100.00% ({}/{})",
-        len_directories, len_directories,
-    );
+    if status_line.is_due(true) {
+        status_line.draw(&format!(
+            "Creating directories: 100.00% ({}/{})",
+            len_directories, len_directories,
+        ));
+    }
+    status_line.finish();
     failed_directories
 }
 
+/// Recreate special files on the target with the kind, permissions and device number of their
+/// source. Returns the ones which couldn't be created, e.g. device nodes without the privileges
+/// to create them.
+pub(crate) fn create_specials<S: Storage, T: Storage>(
+    source_storage: &S,
+    target_storage: &T,
+    specials: &[SpecialFile],
+    output: &Output,
+) -> Vec<Failure<SpecialFile>> {
+    let mut failed_specials = Vec::new();
+    for special in specials {
+        let result = source_storage
+            .metadata(&special.source)
+            .and_then(|metadata| target_storage.create_special(&special.target, &metadata));
+        match result {
+            Ok(_) => {
+                if output.shows(Verbosity::Normal) {
+                    println!(
+                        "Special file created: {} ({})",
+                        special.target.display(),
+                        special.kind
+                    );
+                }
+            }
+            Err(e) => {
+                if output.shows(Verbosity::Verbose) {
+                    println!(
+                        "Failed to create {} {}: {e}",
+                        special.kind,
+                        special.target.display()
+                    );
+                }
+                failed_specials.push(Failure {
+                    entry: special.clone(),
+                    error: e,
+                });
+            }
+        }
+    }
+    failed_specials
+}
+
+/// Create the planned hard links in the target. An existing file at a link's path is replaced.
+/// Returns the files which were linked, and the links which can't be created, e.g. because their
+/// original failed to copy or the target doesn't support hard links, as files to copy instead.
+pub(crate) fn create_hard_links<T: Storage>(
+    target_storage: &T,
+    hard_links: &[HardLink],
+    output: &Output,
+) -> (Vec<FileToCopy>, Vec<FileToCopy>) {
+    let mut linked_files = Vec::new();
+    let mut unlinked_files = Vec::new();
+    for link in hard_links {
+        let file = FileToCopy {
+            source: link.source.clone(),
+            target: link.target.clone(),
+            size: link.size,
+        };
+        // Nothing is removed while the original is missing, e.g. after it failed to copy
+        let result = target_storage
+            .metadata(&link.original)
+            .and_then(|_| match target_storage.entry_kind(&link.target)? {
+                Some(EntryKind::File) => target_storage.remove_file(&link.target),
+                _ => Ok(()),
+            })
+            .and_then(|_| target_storage.hard_link(&link.original, &link.target));
+        match result {
+            Ok(_) => {
+                if output.shows(Verbosity::Normal) {
+                    println!(
+                        "Hard link created: {} -> {}",
+                        link.target.display(),
+                        link.original.display()
+                    );
+                }
+                linked_files.push(file);
+            }
+            Err(e) => {
+                if output.shows(Verbosity::Normal) {
+                    println!(
+                        "Failed to create hard link {}, copying instead: {e}",
+                        link.target.display()
+                    );
+                }
+                unlinked_files.push(file);
+            }
+        }
+    }
+    (linked_files, unlinked_files)
+}
+
+/// Copy a single file between two storage backends. Permissions and the last modified timestamp
+/// are carried over, so the copy compares as up to date on the next run on every platform.
+fn copy_file<S: Storage, T: Storage>(
+    source_storage: &S,
+    target_storage: &T,
+    file: &FileToCopy,
+    progress: &mut ByteProgress,
+) -> io::Result<u64> {
+    let source_metadata = source_storage.metadata(&file.source)?;
+    let mut reader = ProgressReader::new(source_storage.read(&file.source)?, progress);
+    let written = match target_storage.write(&file.target, &mut reader) {
+        Ok(written) => written,
+        Err(e) => {
+            // Don't leave a truncated file behind which would look up to date on the next run
+            let _ = target_storage.remove_file(&file.target);
+            return Err(e);
+        }
+    };
+    // The timestamp first, a read-only mode would prevent setting it. Without both, the copy is
+    // removed again, as its fresh timestamp would make it look up to date on the next run.
+    let applied = target_storage
+        .set_modified(&file.target, source_metadata.modified)
+        .and_then(|_| target_storage.set_mode(&file.target, source_metadata.mode));
+    if let Err(e) = applied {
+        let _ = target_storage.remove_file(&file.target);
+        return Err(e);
+    }
+    Ok(written)
+}
+
 /// Copy files from the provided slice of FileToCopy structs
-pub(crate) fn copy_files(list_of_files: &[FileToCopy]) -> Vec<FileToCopy> {
+pub(crate) fn copy_files<S: Storage, T: Storage>(
+    source_storage: &S,
+    target_storage: &T,
+    list_of_files: &[FileToCopy],
+    output: &Output,
+) -> Vec<Failure<FileToCopy>> {
     let len_files = list_of_files.len();
 
     if len_files == 0 {
@@ -128,33 +1139,140 @@ pub(crate) fn copy_files(list_of_files: &[FileToCopy]) -> Vec<FileToCopy> {
     }
 
     let mut failed_files = Vec::new();
-
-    for (i, file) in list_of_files.iter().enumerate() {
-        print!(
-            "\rCopying files: {:.2}% ({}/{})",
-            i as f64 / len_files as f64 * 100.,
-            i,
-            len_files
-        );
-        // Make sure it flushes immediately
-        io::Write::flush(&mut io::stdout()).unwrap();
-        match fs::copy(&file.source, &file.target) {
-            Ok(_) => println!("\rFile copied: {}", file.source.display()),
-            Err(_) => failed_files.push(file.clone()),
+    let total_bytes = list_of_files.iter().map(|file| file.size).sum();
+    let mut progress = ByteProgress::new(total_bytes, len_files, output.live_progress);
+
+    for file in list_of_files {
+        progress.start_file(file.size);
+        match copy_file(source_storage, target_storage, file, &mut progress) {
+            Ok(_) => {
+                if output.shows(Verbosity::Verbose) {
+                    progress.println(&format!(
+                        "File copied: {} ({})",
+                        file.source.display(),
+                        format_bytes(file.size)
+                    ));
+                } else if output.shows(Verbosity::Normal) {
+                    progress.println(&format!("File copied: {}", file.source.display()));
+                }
+            }
+            Err(e) => {
+                if output.shows(Verbosity::Verbose) {
+                    progress.println(&format!("Failed to copy {}: {e}", file.source.display()));
+                }
+                failed_files.push(Failure {
+                    entry: file.clone(),
+                    error: e,
+                });
+            }
         }
+        progress.finish_file();
     }
-    println!("\rCopying files: 100.00% ({}/{})", len_files, len_files);
+    progress.finish();
 
     failed_files
 }
 
+/// Return whether a failed entry is worth retrying. Besides transient errors that's the case for
+/// entries inside a directory which could be created on retry, since they most likely only
+/// failed because their parent was missing.
+fn is_retryable(
+    path: &Path,
+    error: &io::Error,
+    recovered_directories: &[DirectoryToCreate],
+) -> bool {
+    is_transient(error)
+        || recovered_directories
+            .iter()
+            .any(|directory| path.starts_with(&directory.path))
+}
+
+/// Retry creating the directories which failed with a transient error. Returns the recovered
+/// directories and the ones which failed permanently.
+pub(crate) fn retry_directories<T: Storage>(
+    target_storage: &T,
+    failures: Vec<Failure<DirectoryToCreate>>,
+    policy: &RetryPolicy,
+    output: &Output,
+) -> (Vec<DirectoryToCreate>, Vec<Failure<DirectoryToCreate>>) {
+    let mut recovered = Vec::new();
+    let mut permanent = Vec::new();
+    // Parents come before their children, so a recovered parent is known when its children
+    // are retried
+    for failure in failures {
+        if !is_retryable(&failure.entry.path, &failure.error, &recovered) {
+            permanent.push(failure);
+            continue;
+        }
+        match policy.retry(|| target_storage.create_dir(&failure.entry.path)) {
+            Ok(_) => {
+                if output.shows(Verbosity::Normal) {
+                    println!(
+                        "Directory created on retry: {}",
+                        failure.entry.path.display()
+                    );
+                }
+                recovered.push(failure.entry);
+            }
+            Err(error) => permanent.push(Failure {
+                entry: failure.entry,
+                error,
+            }),
+        }
+    }
+    (recovered, permanent)
+}
+
+/// Retry copying the files which failed with a transient error or whose directory was only
+/// created on retry. Returns the recovered files and the ones which failed permanently.
+pub(crate) fn retry_files<S: Storage, T: Storage>(
+    source_storage: &S,
+    target_storage: &T,
+    failures: Vec<Failure<FileToCopy>>,
+    recovered_directories: &[DirectoryToCreate],
+    policy: &RetryPolicy,
+    output: &Output,
+) -> (Vec<FileToCopy>, Vec<Failure<FileToCopy>>) {
+    let (retryable, mut permanent): (Vec<_>, Vec<_>) = failures.into_iter().partition(|failure| {
+        is_retryable(&failure.entry.target, &failure.error, recovered_directories)
+    });
+    let mut recovered = Vec::new();
+    if retryable.is_empty() {
+        return (recovered, permanent);
+    }
+
+    let total_bytes = retryable.iter().map(|failure| failure.entry.size).sum();
+    let mut progress = ByteProgress::new(total_bytes, retryable.len(), output.live_progress);
+    for failure in retryable {
+        let file = failure.entry;
+        progress.start_file(file.size);
+        let result = policy.retry(|| {
+            progress.restart_file();
+            copy_file(source_storage, target_storage, &file, &mut progress)
+        });
+        progress.finish_file();
+        match result {
+            Ok(_) => {
+                if output.shows(Verbosity::Normal) {
+                    progress.println(&format!("File copied on retry: {}", file.source.display()));
+                }
+                recovered.push(file);
+            }
+            Err(error) => permanent.push(Failure { entry: file, error }),
+        }
+    }
+    progress.finish();
+    (recovered, permanent)
+}
+
 #[cfg(test)]
 mod tests {
-    use std::env;
     use std::thread::sleep;
-    use std::time::Duration;
+    use std::time::{Duration, SystemTime};
+    use std::{env, fs};
 
     use super::*;
+    use crate::storage::{LocalStorage, MemoryStorage};
 
     #[test]
     fn test_get_files_and_directories() {
@@ -189,7 +1307,7 @@ mod tests {
         let source_file_1 = source_dir_path.join("test_1.txt");
         let source_file_1_content = b"This is some newer text";
         fs::write(&target_file_1, b"This is some text").unwrap();
-        sleep(Duration::from_nanos(1)); // waiting so the source file is newer
+        sleep(Duration::from_millis(20)); // waiting so the source file is newer
         fs::write(&source_file_1, source_file_1_content).unwrap();
 
         // Write files that should stay the same
@@ -198,6 +1316,13 @@ mod tests {
         let source_file_2_content = b"This is unchanged text";
         fs::write(&target_file_2, source_file_2_content).unwrap();
         fs::copy(&target_file_2, &source_file_2).unwrap();
+        // fs::copy only keeps the timestamp on some platforms
+        LocalStorage
+            .set_modified(
+                &source_file_2,
+                fs::metadata(&target_file_2).unwrap().modified().unwrap(),
+            )
+            .unwrap();
         assert_eq!(
             fs::metadata(&source_file_2).unwrap().modified().unwrap(),
             fs::metadata(&target_file_2).unwrap().modified().unwrap(),
@@ -209,6 +1334,13 @@ mod tests {
         let source_file_3_content = b"This is unchanged text too";
         fs::write(&target_file_3, source_file_3_content).unwrap();
         fs::copy(&target_file_3, &source_file_3).unwrap();
+        // fs::copy only keeps the timestamp on some platforms
+        LocalStorage
+            .set_modified(
+                &source_file_3,
+                fs::metadata(&target_file_3).unwrap().modified().unwrap(),
+            )
+            .unwrap();
         assert_eq!(
             fs::metadata(&source_file_3).unwrap().modified().unwrap(),
             fs::metadata(&target_file_3).unwrap().modified().unwrap(),
@@ -219,7 +1351,7 @@ mod tests {
         let source_file_4 = source_subdir_1_path.join("test_4.txt");
         let source_file_4_content = b"4 This is some changed text in subdirectory 1";
         fs::write(&target_file_4, b"4 This is some text in subdirectory 1").unwrap();
-        sleep(Duration::from_nanos(1)); // waiting so the source file is newer
+        sleep(Duration::from_millis(20)); // waiting so the source file is newer
         fs::write(&source_file_4, source_file_4_content).unwrap();
 
         // Write a file that should be created in subdirectory 1
@@ -239,8 +1371,14 @@ mod tests {
         let target_file_7_content = b"7 This is a relict that should not be touched";
         fs::write(&target_file_7, target_file_7_content).unwrap();
 
-        let mut results =
-            get_files_and_directories(&source_dir_path, &target_dir_path, &HashSet::new()).unwrap();
+        let mut results = get_files_and_directories(
+            &LocalStorage,
+            &LocalStorage,
+            &source_dir_path,
+            &target_dir_path,
+            &ScanOptions::default(),
+        )
+        .unwrap();
 
         results.files.sort_by_key(|val| val.source.clone());
 
@@ -250,24 +1388,37 @@ mod tests {
                 files: vec![
                     FileToCopy {
                         source: source_file_4,
-                        target: target_file_4,
+                        target: target_file_4.clone(),
+                        size: source_file_4_content.len() as u64,
                     },
                     FileToCopy {
                         source: source_file_5,
                         target: target_file_5,
+                        size: source_file_5_content.len() as u64,
                     },
                     FileToCopy {
                         source: source_file_6,
                         target: target_file_6,
+                        size: source_file_6_content.len() as u64,
                     },
                     FileToCopy {
                         source: source_file_1,
-                        target: target_file_1,
+                        target: target_file_1.clone(),
+                        size: source_file_1_content.len() as u64,
                     },
                 ],
                 directories: vec![DirectoryToCreate {
                     path: target_subdir_2_path,
-                }]
+                }],
+                type_conflicts: vec![],
+                filtered: BTreeMap::new(),
+                name_collisions: vec![],
+                scanned_files: 6,
+                unchanged_files: 2,
+                updated_targets: HashSet::from([target_file_1, target_file_4]),
+                specials: vec![],
+                skipped_specials: vec![],
+                hard_links: vec![],
             }
         );
 
@@ -310,7 +1461,7 @@ mod tests {
         let source_file_1 = source_dir_path.join("test_1.txt");
         let source_file_1_content = b"This is some newer text";
         fs::write(&target_file_1, b"This is some text").unwrap();
-        sleep(Duration::from_nanos(1)); // waiting so the source file is newer
+        sleep(Duration::from_millis(20)); // waiting so the source file is newer
         fs::write(&source_file_1, source_file_1_content).unwrap();
 
         // Write files that should stay the same
@@ -319,6 +1470,13 @@ mod tests {
         let source_file_2_content = b"This is unchanged text";
         fs::write(&target_file_2, source_file_2_content).unwrap();
         fs::copy(&target_file_2, &source_file_2).unwrap();
+        // fs::copy only keeps the timestamp on some platforms
+        LocalStorage
+            .set_modified(
+                &source_file_2,
+                fs::metadata(&target_file_2).unwrap().modified().unwrap(),
+            )
+            .unwrap();
         assert_eq!(
             fs::metadata(&source_file_2).unwrap().modified().unwrap(),
             fs::metadata(&target_file_2).unwrap().modified().unwrap(),
@@ -330,6 +1488,13 @@ mod tests {
         let source_file_3_content = b"This is unchanged text too";
         fs::write(&target_file_3, source_file_3_content).unwrap();
         fs::copy(&target_file_3, &source_file_3).unwrap();
+        // fs::copy only keeps the timestamp on some platforms
+        LocalStorage
+            .set_modified(
+                &source_file_3,
+                fs::metadata(&target_file_3).unwrap().modified().unwrap(),
+            )
+            .unwrap();
         assert_eq!(
             fs::metadata(&source_file_3).unwrap().modified().unwrap(),
             fs::metadata(&target_file_3).unwrap().modified().unwrap(),
@@ -340,7 +1505,7 @@ mod tests {
         let source_file_4 = source_subdir_1_path.join("test_4.txt");
         let source_file_4_content = b"4 This is some changed text in subdirectory 1";
         fs::write(&target_file_4, b"4 This is some text in subdirectory 1").unwrap();
-        sleep(Duration::from_nanos(1)); // waiting so the source file is newer
+        sleep(Duration::from_millis(20)); // waiting so the source file is newer
         fs::write(&source_file_4, source_file_4_content).unwrap();
 
         // Write a file that should be created in subdirectory 1
@@ -364,11 +1529,19 @@ mod tests {
         let source_file_8_content = b"8 This file should be ignored";
         fs::write(&source_file_8, source_file_8_content).unwrap();
 
-        let directories_to_skip = HashSet::from([source_subdir_4_path.clone()]);
+        let options = ScanOptions {
+            directories_to_skip: HashSet::from([source_subdir_4_path.clone()]),
+            ..Default::default()
+        };
 
-        let mut results =
-            get_files_and_directories(&source_dir_path, &target_dir_path, &directories_to_skip)
-                .unwrap();
+        let mut results = get_files_and_directories(
+            &LocalStorage,
+            &LocalStorage,
+            &source_dir_path,
+            &target_dir_path,
+            &options,
+        )
+        .unwrap();
 
         results.files.sort_by_key(|val| val.source.clone());
 
@@ -378,24 +1551,37 @@ mod tests {
                 files: vec![
                     FileToCopy {
                         source: source_file_4,
-                        target: target_file_4,
+                        target: target_file_4.clone(),
+                        size: source_file_4_content.len() as u64,
                     },
                     FileToCopy {
                         source: source_file_5,
                         target: target_file_5,
+                        size: source_file_5_content.len() as u64,
                     },
                     FileToCopy {
                         source: source_file_6,
                         target: target_file_6,
+                        size: source_file_6_content.len() as u64,
                     },
                     FileToCopy {
                         source: source_file_1,
-                        target: target_file_1,
+                        target: target_file_1.clone(),
+                        size: source_file_1_content.len() as u64,
                     },
                 ],
                 directories: vec![DirectoryToCreate {
                     path: target_subdir_2_path,
-                }]
+                }],
+                type_conflicts: vec![],
+                filtered: BTreeMap::new(),
+                name_collisions: vec![],
+                scanned_files: 6,
+                unchanged_files: 2,
+                updated_targets: HashSet::from([target_file_1, target_file_4]),
+                specials: vec![],
+                skipped_specials: vec![],
+                hard_links: vec![],
             }
         );
 
@@ -460,10 +1646,12 @@ mod tests {
         ];
 
         // Run the tested function
-        let result = create_directories(&test_input);
+        let result =
+            create_directories(&LocalStorage, &test_input, &Output::new(Verbosity::Normal));
 
         // Check that all directories that are expected to be created exist
-        assert_eq!(result, expected_failed_directories);
+        let failed_directories: Vec<_> = result.into_iter().map(|failure| failure.entry).collect();
+        assert_eq!(failed_directories, expected_failed_directories);
         for i in expected_existing_directories.iter() {
             assert!(fs::exists(i).is_ok())
         }
@@ -532,22 +1720,27 @@ mod tests {
             FileToCopy {
                 source: source_file_1.clone(),
                 target: target_file_1.clone(),
+                size: source_file_1_content.len() as u64,
             },
             FileToCopy {
                 source: source_file_2.clone(),
                 target: target_file_2.clone(),
+                size: source_file_2_content.len() as u64,
             },
             FileToCopy {
                 source: source_file_3.clone(),
                 target: target_file_3.clone(),
+                size: source_file_3_content.len() as u64,
             },
             FileToCopy {
                 source: source_file_4.clone(),
                 target: target_file_4.clone(),
+                size: source_file_4_content.len() as u64,
             },
             FileToCopy {
                 source: source_file_5.clone(),
                 target: target_file_5.clone(),
+                size: 0, // Source file doesn't exist
             },
         ];
 
@@ -555,18 +1748,26 @@ mod tests {
             FileToCopy {
                 source: source_file_4.clone(),
                 target: target_file_4.clone(),
+                size: source_file_4_content.len() as u64,
             },
             FileToCopy {
                 source: source_file_5.clone(),
                 target: target_file_5.clone(),
+                size: 0, // Source file doesn't exist
             },
         ];
 
         // Run the tested function
-        let result = copy_files(&test_input);
+        let result = copy_files(
+            &LocalStorage,
+            &LocalStorage,
+            &test_input,
+            &Output::new(Verbosity::Normal),
+        );
 
         // Check that files expected to fail failed
-        assert_eq!(result, expected_failed_files);
+        let failed_files: Vec<_> = result.into_iter().map(|failure| failure.entry).collect();
+        assert_eq!(failed_files, expected_failed_files);
 
         // Check that nothing happened to the source files content
         assert_eq!(fs::read(&source_file_1).unwrap(), source_file_1_content);
@@ -586,4 +1787,1288 @@ mod tests {
         // Delete all test directories and files
         fs::remove_dir_all(test_dir_path).unwrap();
     }
+
+    #[test]
+    fn test_copy_read_only_file() {
+        let test_dir_path = env::current_dir().unwrap().join("test_dir_read_only");
+        let _ = fs::remove_dir_all(&test_dir_path);
+        let source_path = test_dir_path.join("source");
+        let target_path = test_dir_path.join("target");
+        fs::create_dir_all(&source_path).unwrap();
+        fs::create_dir(&target_path).unwrap();
+        let source_file = source_path.join("read_only.txt");
+        let target_file = target_path.join("read_only.txt");
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        fs::write(&source_file, b"content").unwrap();
+        LocalStorage.set_modified(&source_file, modified).unwrap();
+        LocalStorage.set_mode(&source_file, 0o444).unwrap();
+        let scan = || {
+            get_files_and_directories(
+                &LocalStorage,
+                &LocalStorage,
+                &source_path,
+                &target_path,
+                &ScanOptions::default(),
+            )
+            .unwrap()
+        };
+
+        let results = scan();
+        let output = Output::new(Verbosity::Quiet);
+        assert!(copy_files(&LocalStorage, &LocalStorage, &results.files, &output).is_empty());
+        let metadata = LocalStorage.metadata(&target_file).unwrap();
+        assert_eq!(
+            (metadata.mode, metadata.modified),
+            (LocalStorage.metadata(&source_file).unwrap().mode, modified)
+        );
+        assert!(scan().files.is_empty());
+
+        // Read-only files can't be removed on every platform
+        LocalStorage.set_mode(&source_file, 0o644).unwrap();
+        LocalStorage.set_mode(&target_file, 0o644).unwrap();
+        fs::remove_dir_all(test_dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_sync_with_memory_storage() {
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let new = SystemTime::UNIX_EPOCH + Duration::from_secs(2_000);
+
+        let source = MemoryStorage::new();
+        source.add_dir("/source/empty_dir");
+        source.add_file("/source/changed.txt", b"new content", new);
+        source.add_file("/source/unchanged.txt", b"same content", old);
+        source.add_file("/source/subdir/new.txt", b"new file", new);
+
+        let target = MemoryStorage::new();
+        target.add_file("/target/changed.txt", b"old content", old);
+        target.add_file("/target/unchanged.txt", b"same content", old);
+        target.add_file("/target/relict.txt", b"relict", old);
+
+        let source_path = Path::new("/source");
+        let target_path = Path::new("/target");
+        let results = get_files_and_directories(
+            &source,
+            &target,
+            source_path,
+            target_path,
+            &ScanOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            results,
+            FilesAndDirectories {
+                files: vec![
+                    FileToCopy {
+                        source: source_path.join("changed.txt"),
+                        target: target_path.join("changed.txt"),
+                        size: 11,
+                    },
+                    FileToCopy {
+                        source: source_path.join("subdir/new.txt"),
+                        target: target_path.join("subdir/new.txt"),
+                        size: 8,
+                    },
+                ],
+                directories: vec![
+                    DirectoryToCreate {
+                        path: target_path.join("empty_dir"),
+                    },
+                    DirectoryToCreate {
+                        path: target_path.join("subdir"),
+                    },
+                ],
+                type_conflicts: vec![],
+                filtered: BTreeMap::new(),
+                name_collisions: vec![],
+                scanned_files: 3,
+                unchanged_files: 1,
+                updated_targets: HashSet::from([target_path.join("changed.txt")]),
+                specials: vec![],
+                skipped_specials: vec![],
+                hard_links: vec![],
+            }
+        );
+
+        assert!(create_directories(
+            &target,
+            &results.directories,
+            &Output::new(Verbosity::Quiet)
+        )
+        .is_empty());
+        assert!(copy_files(
+            &source,
+            &target,
+            &results.files,
+            &Output::new(Verbosity::Quiet)
+        )
+        .is_empty());
+
+        assert!(target
+            .metadata(&target_path.join("empty_dir"))
+            .unwrap()
+            .is_dir());
+        assert_eq!(
+            target.content("/target/changed.txt").unwrap(),
+            b"new content"
+        );
+        assert_eq!(
+            target.content("/target/subdir/new.txt").unwrap(),
+            b"new file"
+        );
+        assert_eq!(
+            target.content("/target/unchanged.txt").unwrap(),
+            b"same content"
+        );
+        assert_eq!(target.content("/target/relict.txt").unwrap(), b"relict");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_special_files() {
+        use crate::storage::Metadata;
+
+        let test_dir_path = env::current_dir().unwrap().join("test_dir_specials");
+        let _ = fs::remove_dir_all(&test_dir_path);
+        let source_path = test_dir_path.join("source");
+        let target_path = test_dir_path.join("target");
+        fs::create_dir_all(&source_path).unwrap();
+        fs::create_dir(&target_path).unwrap();
+        let fifo_metadata = Metadata {
+            kind: EntryKind::Fifo,
+            len: 0,
+            modified: SystemTime::now(),
+            mode: 0o640,
+            rdev: 0,
+            link_id: None,
+        };
+        LocalStorage
+            .create_special(&source_path.join("pipe"), &fifo_metadata)
+            .unwrap();
+        fs::write(source_path.join("file.txt"), b"file").unwrap();
+        let scan = |specials| {
+            get_files_and_directories(
+                &LocalStorage,
+                &LocalStorage,
+                &source_path,
+                &target_path,
+                &ScanOptions {
+                    specials,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        let pipe = SpecialFile {
+            source: source_path.join("pipe"),
+            target: target_path.join("pipe"),
+            kind: EntryKind::Fifo,
+        };
+
+        // Skipped by default instead of being read as a file, which would block
+        let results = scan(false);
+        assert_eq!(results.files.len(), 1);
+        assert!(results.specials.is_empty());
+        assert_eq!(results.skipped_specials, vec![pipe.clone()]);
+
+        let results = scan(true);
+        assert_eq!(results.files.len(), 1);
+        assert_eq!(results.specials, vec![pipe.clone()]);
+        let output = Output::new(Verbosity::Quiet);
+        assert!(
+            create_specials(&LocalStorage, &LocalStorage, &results.specials, &output).is_empty()
+        );
+        let metadata = LocalStorage.metadata(&pipe.target).unwrap();
+        assert_eq!((metadata.kind, metadata.mode), (EntryKind::Fifo, 0o640));
+
+        // An existing FIFO is left alone
+        assert!(scan(true).specials.is_empty());
+
+        // The missing parent of a listed FIFO isn't pruned as empty
+        fs::create_dir(source_path.join("dir")).unwrap();
+        LocalStorage
+            .create_special(&source_path.join("dir/pipe"), &fifo_metadata)
+            .unwrap();
+        let results = get_files_and_directories(
+            &LocalStorage,
+            &LocalStorage,
+            &source_path,
+            &target_path,
+            &ScanOptions {
+                specials: true,
+                prune_empty_dirs: true,
+                sub_paths: vec![PathBuf::from("dir/pipe")],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            results.directories,
+            vec![DirectoryToCreate {
+                path: target_path.join("dir"),
+            }]
+        );
+        assert_eq!(results.specials.len(), 1);
+
+        fs::remove_dir_all(test_dir_path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hard_links() {
+        let test_dir_path = env::current_dir().unwrap().join("test_dir_hard_links");
+        let _ = fs::remove_dir_all(&test_dir_path);
+        let source_path = test_dir_path.join("source");
+        let target_path = test_dir_path.join("target");
+        fs::create_dir_all(source_path.join("dir")).unwrap();
+        fs::create_dir(&target_path).unwrap();
+        fs::write(source_path.join("a.txt"), b"shared").unwrap();
+        fs::hard_link(source_path.join("a.txt"), source_path.join("dir/b.txt")).unwrap();
+        fs::write(source_path.join("c.txt"), b"single").unwrap();
+        let scan = || {
+            get_files_and_directories(
+                &LocalStorage,
+                &LocalStorage,
+                &source_path,
+                &target_path,
+                &ScanOptions {
+                    hard_links: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        let link = HardLink {
+            source: source_path.join("dir/b.txt"),
+            target: target_path.join("dir/b.txt"),
+            original: target_path.join("a.txt"),
+            size: 6,
+        };
+
+        let results = scan();
+        let copied: Vec<_> = results.files.iter().map(|file| &file.source).collect();
+        assert_eq!(
+            copied,
+            [&source_path.join("a.txt"), &source_path.join("c.txt")]
+        );
+        assert_eq!(results.hard_links, vec![link.clone()]);
+
+        let output = Output::new(Verbosity::Quiet);
+        assert!(create_directories(&LocalStorage, &results.directories, &output).is_empty());
+        assert!(copy_files(&LocalStorage, &LocalStorage, &results.files, &output).is_empty());
+        let (linked_files, unlinked_files) =
+            create_hard_links(&LocalStorage, &results.hard_links, &output);
+        assert_eq!(linked_files.len(), 1);
+        assert!(unlinked_files.is_empty());
+        let link_id = |path: &Path| LocalStorage.metadata(path).unwrap().link_id;
+        assert!(link_id(&link.target).is_some());
+        assert_eq!(link_id(&link.target), link_id(&link.original));
+
+        // Already linked, so nothing is left to do
+        let results = scan();
+        assert!(results.files.is_empty() && results.hard_links.is_empty());
+        assert_eq!(results.unchanged_files, 3);
+
+        // A separate copy from a run without --hard-links is replaced by a link
+        fs::remove_file(&link.target).unwrap();
+        fs::copy(&link.original, &link.target).unwrap();
+        let results = scan();
+        assert_eq!(results.hard_links, vec![link.clone()]);
+        assert!(
+            create_hard_links(&LocalStorage, &results.hard_links, &output)
+                .1
+                .is_empty()
+        );
+        assert_eq!(link_id(&link.target), link_id(&link.original));
+
+        fs::remove_dir_all(test_dir_path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hard_links_to_conflicting_original() {
+        let test_dir_path = env::current_dir()
+            .unwrap()
+            .join("test_dir_hard_links_conflict");
+        let _ = fs::remove_dir_all(&test_dir_path);
+        let source_path = test_dir_path.join("source");
+        let target_path = test_dir_path.join("target");
+        fs::create_dir_all(&source_path).unwrap();
+        fs::create_dir_all(target_path.join("a.txt")).unwrap();
+        fs::write(source_path.join("a.txt"), b"shared").unwrap();
+        fs::hard_link(source_path.join("a.txt"), source_path.join("b.txt")).unwrap();
+
+        // The first name isn't copied, so the second one is copied instead of linked to it
+        let results = get_files_and_directories(
+            &LocalStorage,
+            &LocalStorage,
+            &source_path,
+            &target_path,
+            &ScanOptions {
+                hard_links: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(results.type_conflicts.len(), 1);
+        assert!(results.hard_links.is_empty());
+        let copied: Vec<_> = results.files.iter().map(|file| &file.source).collect();
+        assert_eq!(copied, [&source_path.join("b.txt")]);
+
+        fs::remove_dir_all(test_dir_path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hard_links_keep_pruned_sub_path_parents() {
+        let test_dir_path = env::current_dir()
+            .unwrap()
+            .join("test_dir_hard_links_pruned");
+        let _ = fs::remove_dir_all(&test_dir_path);
+        let source_path = test_dir_path.join("source");
+        let target_path = test_dir_path.join("target");
+        fs::create_dir_all(source_path.join("a")).unwrap();
+        fs::create_dir_all(source_path.join("z/sub")).unwrap();
+        fs::create_dir(&target_path).unwrap();
+        fs::write(source_path.join("a/o"), b"shared").unwrap();
+        fs::hard_link(source_path.join("a/o"), source_path.join("z/sub/l")).unwrap();
+
+        let results = get_files_and_directories(
+            &LocalStorage,
+            &LocalStorage,
+            &source_path,
+            &target_path,
+            &ScanOptions {
+                hard_links: true,
+                prune_empty_dirs: true,
+                sub_paths: vec![PathBuf::from("a/o"), PathBuf::from("z/sub/l")],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let directories: Vec<_> = results
+            .directories
+            .iter()
+            .map(|directory| &directory.path)
+            .collect();
+        assert_eq!(
+            directories,
+            [
+                &target_path.join("a"),
+                &target_path.join("z"),
+                &target_path.join("z/sub")
+            ]
+        );
+        assert_eq!(results.hard_links.len(), 1);
+
+        fs::remove_dir_all(test_dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_hard_links_fall_back_to_copies() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let source = MemoryStorage::new();
+        source.add_file("/source/a.txt", b"shared", modified);
+        source.add_file("/source/b.txt", b"shared", modified);
+        let target = MemoryStorage::new();
+        target.add_file("/target/a.txt", b"shared", modified);
+        let link = HardLink {
+            source: PathBuf::from("/source/b.txt"),
+            target: PathBuf::from("/target/b.txt"),
+            original: PathBuf::from("/target/a.txt"),
+            size: 6,
+        };
+
+        // Memory storage has no hard links, like an archive
+        let output = Output::new(Verbosity::Quiet);
+        let (linked_files, unlinked_files) = create_hard_links(&target, &[link], &output);
+        assert!(linked_files.is_empty());
+        assert_eq!(
+            unlinked_files,
+            vec![FileToCopy {
+                source: PathBuf::from("/source/b.txt"),
+                target: PathBuf::from("/target/b.txt"),
+                size: 6,
+            }]
+        );
+        assert!(copy_files(&source, &target, &unlinked_files, &output).is_empty());
+        assert_eq!(target.content("/target/b.txt").unwrap(), b"shared");
+    }
+
+    #[test]
+    fn test_remove_source_files() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let source = MemoryStorage::new();
+        source.add_file("/source/ingest/a.txt", b"a", modified);
+        source.add_file("/source/ingest/deep/b.txt", b"b", modified);
+        source.add_file("/source/changed.txt", b"before", modified);
+        let target = MemoryStorage::new();
+        target.add_dir("/target");
+
+        let output = Output::new(Verbosity::Quiet);
+        let results = get_files_and_directories(
+            &source,
+            &target,
+            Path::new("/source"),
+            Path::new("/target"),
+            &ScanOptions::default(),
+        )
+        .unwrap();
+        assert!(create_directories(&target, &results.directories, &output).is_empty());
+        assert!(copy_files(&source, &target, &results.files, &output).is_empty());
+
+        // Changed after it was copied, so the copy is outdated
+        source.add_file(
+            "/source/changed.txt",
+            b"after",
+            modified + Duration::from_secs(1),
+        );
+
+        let (removed, kept) =
+            remove_source_files(&source, &target, results.files, Duration::ZERO, &output);
+        assert_eq!(
+            removed,
+            vec![
+                PathBuf::from("/source/ingest/a.txt"),
+                PathBuf::from("/source/ingest/deep/b.txt"),
+            ]
+        );
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].entry.source, PathBuf::from("/source/changed.txt"));
+        assert_eq!(target.content("/target/ingest/deep/b.txt").unwrap(), b"b");
+
+        assert!(
+            remove_empty_source_directories(&source, Path::new("/source"), &removed, &output)
+                .is_empty()
+        );
+        assert_eq!(
+            source.list(Path::new("/source")).unwrap(),
+            vec![PathBuf::from("/source/changed.txt")]
+        );
+    }
+
+    #[test]
+    fn test_remove_source_files_on_coarse_target() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::new(1_001, 500_000_000);
+        let source = MemoryStorage::new();
+        source.add_file("/source/a.txt", b"a", modified);
+        // A FAT target stores the timestamp rounded up to two seconds
+        let target = MemoryStorage::new();
+        target.add_file(
+            "/target/a.txt",
+            b"a",
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_002),
+        );
+        let files = vec![FileToCopy {
+            source: PathBuf::from("/source/a.txt"),
+            target: PathBuf::from("/target/a.txt"),
+            size: 1,
+        }];
+        let output = Output::new(Verbosity::Quiet);
+
+        let (removed, kept) =
+            remove_source_files(&source, &target, files.clone(), Duration::ZERO, &output);
+        assert!(removed.is_empty());
+        assert_eq!(kept.len(), 1);
+
+        let (removed, kept) =
+            remove_source_files(&source, &target, files, Duration::from_secs(2), &output);
+        assert_eq!(removed, vec![PathBuf::from("/source/a.txt")]);
+        assert!(kept.is_empty());
+    }
+
+    #[test]
+    fn test_type_conflicts() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let source = MemoryStorage::new();
+        source.add_file("/source/dir_in_source/file.txt", b"file", modified);
+        source.add_file("/source/file_in_source", b"file", modified);
+
+        let target = MemoryStorage::new();
+        target.add_file("/target/dir_in_source", b"in the way", modified);
+        target.add_file("/target/file_in_source/old.txt", b"in the way", modified);
+
+        let source_path = Path::new("/source");
+        let target_path = Path::new("/target");
+        let expected_conflicts = vec![
+            TypeConflict {
+                source: source_path.join("dir_in_source"),
+                target: target_path.join("dir_in_source"),
+                source_kind: EntryKind::Directory,
+                target_kind: EntryKind::File,
+            },
+            TypeConflict {
+                source: source_path.join("file_in_source"),
+                target: target_path.join("file_in_source"),
+                source_kind: EntryKind::File,
+                target_kind: EntryKind::Directory,
+            },
+        ];
+
+        // Without replacing, conflicts are only reported
+        let results = get_files_and_directories(
+            &source,
+            &target,
+            source_path,
+            target_path,
+            &ScanOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            results,
+            FilesAndDirectories {
+                files: vec![],
+                directories: vec![],
+                type_conflicts: expected_conflicts.clone(),
+                filtered: BTreeMap::new(),
+                name_collisions: vec![],
+                scanned_files: 1,
+                ..Default::default()
+            }
+        );
+
+        // With replacing, the source entries are planned as if the target didn't exist
+        let options = ScanOptions {
+            replace_type_conflicts: true,
+            ..Default::default()
+        };
+        let results =
+            get_files_and_directories(&source, &target, source_path, target_path, &options)
+                .unwrap();
+        assert_eq!(results.type_conflicts, expected_conflicts);
+
+        let output = Output::new(Verbosity::Quiet);
+        assert!(resolve_type_conflicts(&target, &results.type_conflicts, &output).is_empty());
+        assert!(create_directories(&target, &results.directories, &output).is_empty());
+        assert!(copy_files(&source, &target, &results.files, &output).is_empty());
+
+        assert!(target
+            .metadata(&target_path.join("dir_in_source"))
+            .unwrap()
+            .is_dir());
+        assert_eq!(
+            target.content("/target/dir_in_source/file.txt").unwrap(),
+            b"file"
+        );
+        assert_eq!(target.content("/target/file_in_source").unwrap(), b"file");
+        assert!(target
+            .entry_kind(&target_path.join("file_in_source/old.txt"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_prune_empty_dirs() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let source = MemoryStorage::new();
+        source.add_dir("/source/empty/nested_empty");
+        source.add_file("/source/with_file/nested_empty/.keep", b"", modified);
+        source.add_dir("/source/with_file/nested_empty_too");
+
+        let target = MemoryStorage::new();
+        target.add_dir("/target");
+
+        let options = ScanOptions {
+            prune_empty_dirs: true,
+            ..Default::default()
+        };
+        let results = get_files_and_directories(
+            &source,
+            &target,
+            Path::new("/source"),
+            Path::new("/target"),
+            &options,
+        )
+        .unwrap();
+
+        assert_eq!(
+            results.directories,
+            vec![
+                DirectoryToCreate {
+                    path: PathBuf::from("/target/with_file"),
+                },
+                DirectoryToCreate {
+                    path: PathBuf::from("/target/with_file/nested_empty"),
+                },
+            ]
+        );
+        assert_eq!(results.files.len(), 1);
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let source = MemoryStorage::new();
+        source.add_file("/source/top.txt", b"", modified);
+        source.add_file("/source/a/one.txt", b"", modified);
+        source.add_file("/source/a/b/two.txt", b"", modified);
+
+        let target = MemoryStorage::new();
+        target.add_dir("/target");
+
+        let scan = |max_depth| {
+            let options = ScanOptions {
+                max_depth: Some(max_depth),
+                ..Default::default()
+            };
+            get_files_and_directories(
+                &source,
+                &target,
+                Path::new("/source"),
+                Path::new("/target"),
+                &options,
+            )
+            .unwrap()
+        };
+
+        let results = scan(0);
+        assert!(results.directories.is_empty());
+        assert_eq!(
+            results.files.iter().map(|f| &f.target).collect::<Vec<_>>(),
+            vec![Path::new("/target/top.txt")]
+        );
+
+        let results = scan(1);
+        assert_eq!(
+            results.directories,
+            vec![DirectoryToCreate {
+                path: PathBuf::from("/target/a"),
+            }]
+        );
+        assert_eq!(results.files.len(), 2);
+    }
+
+    #[test]
+    fn test_sub_paths() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let source = MemoryStorage::new();
+        source.add_file("/source/ignored.txt", b"", modified);
+        source.add_file("/source/a/b/c/file.txt", b"", modified);
+        source.add_file("/source/a/b/other.txt", b"", modified);
+        source.add_file("/source/a/single.txt", b"", modified);
+
+        let target = MemoryStorage::new();
+        target.add_dir("/target");
+
+        let options = ScanOptions {
+            // The nested path is already covered by "a/b"
+            sub_paths: vec![
+                PathBuf::from("a/b/c"),
+                PathBuf::from("a/single.txt"),
+                PathBuf::from("a/b"),
+            ],
+            ..Default::default()
+        };
+        let results = get_files_and_directories(
+            &source,
+            &target,
+            Path::new("/source"),
+            Path::new("/target"),
+            &options,
+        )
+        .unwrap();
+
+        // The missing parent "a" is only planned once
+        assert_eq!(
+            results.directories,
+            vec![
+                DirectoryToCreate {
+                    path: PathBuf::from("/target/a"),
+                },
+                DirectoryToCreate {
+                    path: PathBuf::from("/target/a/b"),
+                },
+                DirectoryToCreate {
+                    path: PathBuf::from("/target/a/b/c"),
+                },
+            ]
+        );
+        let mut targets: Vec<_> = results.files.iter().map(|f| f.target.clone()).collect();
+        targets.sort();
+        assert_eq!(
+            targets,
+            vec![
+                PathBuf::from("/target/a/b/c/file.txt"),
+                PathBuf::from("/target/a/b/other.txt"),
+                PathBuf::from("/target/a/single.txt"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_filtered_files_are_counted() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let source = MemoryStorage::new();
+        source.add_file("/source/small.txt", b"1", modified);
+        source.add_file("/source/sub/medium.txt", b"12345", modified);
+        source.add_file("/source/sub/large.txt", b"1234567890", modified);
+        source.add_file("/source/sub/old.txt", b"12345", SystemTime::UNIX_EPOCH);
+
+        let target = MemoryStorage::new();
+        target.add_dir("/target");
+
+        let options = ScanOptions {
+            filter: FileFilter {
+                min_size: Some(2),
+                max_size: Some(5),
+                newer_than: Some(modified),
+                older_than: None,
+            },
+            ..Default::default()
+        };
+        let results = get_files_and_directories(
+            &source,
+            &target,
+            Path::new("/source"),
+            Path::new("/target"),
+            &options,
+        )
+        .unwrap();
+
+        assert_eq!(
+            results.files,
+            vec![FileToCopy {
+                source: PathBuf::from("/source/sub/medium.txt"),
+                target: PathBuf::from("/target/sub/medium.txt"),
+                size: 5,
+            }]
+        );
+        assert_eq!(
+            results.filtered,
+            BTreeMap::from([
+                (FilterKind::MinSize, 1),
+                (FilterKind::MaxSize, 1),
+                (FilterKind::NewerThan, 1),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_name_collisions_on_case_insensitive_target() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let source = MemoryStorage::new();
+        source.add_file("/source/README.md", b"upper", modified);
+        source.add_file("/source/Readme.md", b"mixed", modified);
+        source.add_file("/source/README (2).md", b"taken", modified);
+        // "café" once precomposed and once with a combining accent
+        source.add_file("/source/caf\u{e9}", b"nfc", modified);
+        source.add_file("/source/cafe\u{301}", b"nfd", modified);
+
+        let target = MemoryStorage::case_insensitive();
+        target.add_dir("/target");
+
+        let scan = |name_collisions| {
+            let options = ScanOptions {
+                name_collisions,
+                ..Default::default()
+            };
+            get_files_and_directories(
+                &source,
+                &target,
+                Path::new("/source"),
+                Path::new("/target"),
+                &options,
+            )
+            .unwrap()
+        };
+
+        let results = scan(NameCollisionAction::Skip);
+        assert_eq!(
+            results.name_collisions,
+            vec![
+                NameCollision {
+                    source: PathBuf::from("/source/Readme.md"),
+                    collides_with: PathBuf::from("/target/README.md"),
+                    renamed_to: None,
+                },
+                NameCollision {
+                    source: PathBuf::from("/source/caf\u{e9}"),
+                    collides_with: PathBuf::from("/target/cafe\u{301}"),
+                    renamed_to: None,
+                },
+            ]
+        );
+        assert_eq!(results.files.len(), 3);
+
+        let results = scan(NameCollisionAction::Rename);
+        assert_eq!(
+            results.name_collisions[0].renamed_to,
+            Some(PathBuf::from("/target/Readme (3).md"))
+        );
+        assert_eq!(results.files.len(), 5);
+
+        // The renamed copies don't collide with anything on the target
+        let failed = copy_files(
+            &source,
+            &target,
+            &results.files,
+            &Output::new(Verbosity::Quiet),
+        );
+        assert!(failed.is_empty());
+        assert_eq!(target.list(Path::new("/target")).unwrap().len(), 5);
+        assert_eq!(target.content("/target/Readme (3).md").unwrap(), b"mixed");
+    }
+
+    #[test]
+    fn test_name_collisions_after_sanitizing() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let source = MemoryStorage::new();
+        source.add_file("/source/a?b", b"ascii", modified);
+        source.add_file("/source/a\u{ff1f}b", b"fullwidth", modified);
+
+        let target = MemoryStorage::new();
+        target.add_dir("/target");
+
+        let scan = |name_collisions| {
+            let options = ScanOptions {
+                name_collisions,
+                target_fs_compat: TargetFsCompat::Ntfs,
+                ..Default::default()
+            };
+            get_files_and_directories(
+                &source,
+                &target,
+                Path::new("/source"),
+                Path::new("/target"),
+                &options,
+            )
+            .unwrap()
+        };
+
+        let results = scan(NameCollisionAction::Skip);
+        assert_eq!(
+            results.name_collisions,
+            vec![NameCollision {
+                source: PathBuf::from("/source/a\u{ff1f}b"),
+                collides_with: PathBuf::from("/target/a\u{ff1f}b"),
+                renamed_to: None,
+            }]
+        );
+        assert_eq!(results.files.len(), 1);
+        assert_eq!(results.files[0].source, PathBuf::from("/source/a?b"));
+
+        let results = scan(NameCollisionAction::Rename);
+        assert_eq!(
+            results.name_collisions[0].renamed_to,
+            Some(PathBuf::from("/target/a\u{ff1f}b (2)"))
+        );
+        assert_eq!(results.files.len(), 2);
+    }
+
+    #[test]
+    fn test_target_fs_compat() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let source = MemoryStorage::new();
+        source.add_file("/source/what?/notes: draft.txt", b"", modified);
+
+        let target = MemoryStorage::new();
+        target.add_dir("/target");
+
+        let options = ScanOptions {
+            target_fs_compat: TargetFsCompat::Ntfs,
+            ..Default::default()
+        };
+        let scan = || {
+            get_files_and_directories(
+                &source,
+                &target,
+                Path::new("/source"),
+                Path::new("/target"),
+                &options,
+            )
+            .unwrap()
+        };
+
+        let results = scan();
+        assert_eq!(
+            results.files[0].target,
+            PathBuf::from("/target/what\u{ff1f}/notes\u{ff1a} draft.txt")
+        );
+        let output = Output::new(Verbosity::Quiet);
+        assert!(create_directories(&target, &results.directories, &output).is_empty());
+        assert!(copy_files(&source, &target, &results.files, &output).is_empty());
+
+        // The renamed copy is found again and is up to date
+        let results = scan();
+        assert!(results.files.is_empty() && results.directories.is_empty());
+        assert_eq!(results.unchanged_files, 1);
+    }
+
+    #[test]
+    fn test_modify_window() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let source = MemoryStorage::new();
+        source.add_file("/source/within.txt", b"", modified + Duration::from_secs(2));
+        source.add_file(
+            "/source/outside.txt",
+            b"",
+            modified + Duration::from_secs(3),
+        );
+
+        // Like a FAT drive, which rounds to two seconds
+        let target = MemoryStorage::new();
+        target.add_file("/target/within.txt", b"", modified);
+        target.add_file("/target/outside.txt", b"", modified);
+
+        let options = ScanOptions {
+            modify_window: Duration::from_secs(2),
+            ..Default::default()
+        };
+        let results = get_files_and_directories(
+            &source,
+            &target,
+            Path::new("/source"),
+            Path::new("/target"),
+            &options,
+        )
+        .unwrap();
+
+        assert_eq!(
+            results.files,
+            vec![FileToCopy {
+                source: PathBuf::from("/source/outside.txt"),
+                target: PathBuf::from("/target/outside.txt"),
+                size: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_names_to_skip() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let source = MemoryStorage::new();
+        source.add_file("/source/app/node_modules/lib/index.js", b"", modified);
+        source.add_file("/source/app/main.js", b"", modified);
+        source.add_file("/source/.DS_Store", b"", modified);
+        source.add_file("/source/app/deep/.DS_Store", b"", modified);
+
+        let target = MemoryStorage::new();
+        target.add_dir("/target");
+
+        let options = ScanOptions {
+            names_to_skip: HashSet::from([".DS_Store".into(), "node_modules".into()]),
+            ..Default::default()
+        };
+        let results = get_files_and_directories(
+            &source,
+            &target,
+            Path::new("/source"),
+            Path::new("/target"),
+            &options,
+        )
+        .unwrap();
+
+        assert_eq!(
+            results.files,
+            vec![FileToCopy {
+                source: PathBuf::from("/source/app/main.js"),
+                target: PathBuf::from("/target/app/main.js"),
+                size: 0,
+            }]
+        );
+        assert_eq!(
+            results.directories,
+            vec![
+                DirectoryToCreate {
+                    path: PathBuf::from("/target/app"),
+                },
+                DirectoryToCreate {
+                    path: PathBuf::from("/target/app/deep"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sub_paths_below_skipped_directories() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let source = MemoryStorage::new();
+        source.add_file("/source/cache/a/file", b"", modified);
+        source.add_file("/source/app/node_modules/lib/index.js", b"", modified);
+        source.add_file("/source/app/main.js", b"", modified);
+
+        let target = MemoryStorage::new();
+        target.add_dir("/target");
+
+        let options = ScanOptions {
+            directories_to_skip: HashSet::from([PathBuf::from("/source/cache")]),
+            names_to_skip: HashSet::from(["node_modules".into()]),
+            sub_paths: vec![
+                PathBuf::from("cache/a/file"),
+                PathBuf::from("app/node_modules/lib/index.js"),
+                PathBuf::from("app/main.js"),
+            ],
+            ..Default::default()
+        };
+        let results = get_files_and_directories(
+            &source,
+            &target,
+            Path::new("/source"),
+            Path::new("/target"),
+            &options,
+        )
+        .unwrap();
+
+        assert_eq!(
+            results.files,
+            vec![FileToCopy {
+                source: PathBuf::from("/source/app/main.js"),
+                target: PathBuf::from("/target/app/main.js"),
+                size: 0,
+            }]
+        );
+        assert_eq!(
+            results.directories,
+            vec![DirectoryToCreate {
+                path: PathBuf::from("/target/app"),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_select_files_that_fit() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let file = |name: &str, size| FileToCopy {
+            source: PathBuf::from("/source").join(name),
+            target: PathBuf::from("/target").join(name),
+            size,
+        };
+        let files = vec![file("a", 60), file("b", 30), file("c", 50), file("d", 10)];
+
+        // Replacing the existing "c" only needs the difference in size
+        let target = MemoryStorage::new();
+        target.add_file("/target/c", &[0; 40], modified);
+        let needed = space_needed(&target, &files);
+        assert_eq!(needed, vec![60, 30, 10, 10]);
+
+        let names = |files: Vec<FileToCopy>| -> Vec<PathBuf> {
+            files.into_iter().map(|file| file.target).collect()
+        };
+        let (fitting, not_fitting) =
+            select_files_that_fit(files.clone(), &needed, 50, FillOrder::Plan);
+        assert_eq!(
+            names(fitting),
+            vec![
+                PathBuf::from("/target/b"),
+                PathBuf::from("/target/c"),
+                PathBuf::from("/target/d")
+            ]
+        );
+        assert_eq!(names(not_fitting), vec![PathBuf::from("/target/a")]);
+
+        // The order goes by file size, the space by what each file actually adds
+        let (fitting, _) = select_files_that_fit(files, &needed, 70, FillOrder::Largest);
+        assert_eq!(
+            names(fitting),
+            vec![PathBuf::from("/target/a"), PathBuf::from("/target/c")]
+        );
+    }
+
+    #[test]
+    fn test_retry_failures() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let source = MemoryStorage::new();
+        source.add_file("/source/busy/file.txt", b"content", modified);
+        source.add_file("/source/denied.txt", b"", modified);
+        let target = MemoryStorage::new();
+        target.add_dir("/target");
+
+        let failure = |path: &str, kind| Failure {
+            entry: DirectoryToCreate {
+                path: PathBuf::from(path),
+            },
+            error: io::Error::from(kind),
+        };
+        let policy = RetryPolicy {
+            retries: 2,
+            delay: Duration::ZERO,
+        };
+        let output = Output::new(Verbosity::Quiet);
+
+        // The nested directory only failed because its busy parent was missing
+        let (recovered, permanent) = retry_directories(
+            &target,
+            vec![
+                failure("/target/busy", io::ErrorKind::ResourceBusy),
+                failure("/target/busy/nested", io::ErrorKind::NotFound),
+                failure("/target/denied", io::ErrorKind::PermissionDenied),
+            ],
+            &policy,
+            &output,
+        );
+        assert_eq!(recovered.len(), 2);
+        assert_eq!(permanent.len(), 1);
+        assert_eq!(permanent[0].entry.path, PathBuf::from("/target/denied"));
+
+        let file_failure = |name: &str, kind| Failure {
+            entry: FileToCopy {
+                source: PathBuf::from("/source").join(name),
+                target: PathBuf::from("/target").join(name),
+                size: 0,
+            },
+            error: io::Error::from(kind),
+        };
+        let (recovered_files, permanent) = retry_files(
+            &source,
+            &target,
+            vec![
+                file_failure("busy/file.txt", io::ErrorKind::NotFound),
+                file_failure("denied.txt", io::ErrorKind::PermissionDenied),
+            ],
+            &recovered,
+            &policy,
+            &output,
+        );
+        assert_eq!(recovered_files.len(), 1);
+        assert_eq!(target.content("/target/busy/file.txt").unwrap(), b"content");
+        assert_eq!(permanent.len(), 1);
+    }
+
+    #[test]
+    fn test_list_only_sub_paths() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let source = MemoryStorage::new();
+        source.add_file("/source/a/b/changed.txt", b"", modified);
+        source.add_file("/source/a/b/unchanged.txt", b"", modified);
+        source.add_file("/source/a/other.txt", b"", modified);
+        source.add_dir("/source/listed_dir/content");
+
+        let target = MemoryStorage::new();
+        target.add_dir("/target");
+
+        let options = ScanOptions {
+            sub_paths: vec![
+                PathBuf::from("a/b/changed.txt"),
+                PathBuf::from("a"),
+                PathBuf::from("a/b/changed.txt"),
+                PathBuf::from("listed_dir"),
+            ],
+            list_only: true,
+            ..Default::default()
+        };
+        let results = get_files_and_directories(
+            &source,
+            &target,
+            Path::new("/source"),
+            Path::new("/target"),
+            &options,
+        )
+        .unwrap();
+
+        // Listed directories are created but not descended into
+        assert_eq!(
+            results.directories,
+            vec![
+                DirectoryToCreate {
+                    path: PathBuf::from("/target/a"),
+                },
+                DirectoryToCreate {
+                    path: PathBuf::from("/target/a/b"),
+                },
+                DirectoryToCreate {
+                    path: PathBuf::from("/target/listed_dir"),
+                },
+            ]
+        );
+        assert_eq!(
+            results.files,
+            vec![FileToCopy {
+                source: PathBuf::from("/source/a/b/changed.txt"),
+                target: PathBuf::from("/target/a/b/changed.txt"),
+                size: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_sorted_scan_and_copy_order() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let source = MemoryStorage::new();
+        source.add_file("/source/b/old_large.txt", &[0; 30], modified);
+        source.add_file("/source/a.txt", &[0; 20], modified + Duration::from_secs(2));
+        source.add_file("/source/c.txt", &[0; 10], modified + Duration::from_secs(1));
+
+        let target = MemoryStorage::new();
+        target.add_dir("/target");
+
+        let mut files = get_files_and_directories(
+            &source,
+            &target,
+            Path::new("/source"),
+            Path::new("/target"),
+            &ScanOptions::default(),
+        )
+        .unwrap()
+        .files;
+        let names = |files: &[FileToCopy]| -> Vec<String> {
+            files
+                .iter()
+                .map(|file| {
+                    file.source
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned()
+                })
+                .collect()
+        };
+        assert_eq!(names(&files), ["a.txt", "old_large.txt", "c.txt"]);
+
+        sort_files(&source, &mut files, CopyOrder::SizeAsc);
+        assert_eq!(names(&files), ["c.txt", "a.txt", "old_large.txt"]);
+        sort_files(&source, &mut files, CopyOrder::SizeDesc);
+        assert_eq!(names(&files), ["old_large.txt", "a.txt", "c.txt"]);
+        sort_files(&source, &mut files, CopyOrder::Mtime);
+        assert_eq!(names(&files), ["a.txt", "c.txt", "old_large.txt"]);
+    }
+
+    #[test]
+    fn test_delete_empty_directories() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let target = MemoryStorage::new();
+        target.add_dir("/target/empty/nested_empty");
+        target.add_dir("/target/kept/nested_empty");
+        target.add_file("/target/with_file/file.txt", b"", modified);
+        target.add_dir("/target/with_file/nested_empty");
+
+        let failed = delete_empty_directories(
+            &target,
+            Path::new("/target"),
+            &|path: &Path| path == Path::new("/target/kept"),
+            &Output::new(Verbosity::Quiet),
+        );
+
+        assert!(failed.is_empty());
+        assert_eq!(
+            target.list(Path::new("/target")).unwrap(),
+            vec![
+                PathBuf::from("/target/kept"),
+                PathBuf::from("/target/with_file")
+            ]
+        );
+        assert_eq!(
+            target.list(Path::new("/target/with_file")).unwrap(),
+            vec![PathBuf::from("/target/with_file/file.txt")]
+        );
+        assert_eq!(
+            target.list(Path::new("/target/kept")).unwrap(),
+            vec![PathBuf::from("/target/kept/nested_empty")]
+        );
+    }
 }
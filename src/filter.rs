@@ -0,0 +1,222 @@
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use crate::storage::Metadata;
+use crate::time::{days_from_civil, days_in_month, from_unix_seconds};
+
+/// Filter which caused a source file to be left out of the plan.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub(crate) enum FilterKind {
+    MinSize,
+    MaxSize,
+    NewerThan,
+    OlderThan,
+}
+
+impl fmt::Display for FilterKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterKind::MinSize => write!(f, "--min-size"),
+            FilterKind::MaxSize => write!(f, "--max-size"),
+            FilterKind::NewerThan => write!(f, "--newer-than"),
+            FilterKind::OlderThan => write!(f, "--older-than"),
+        }
+    }
+}
+
+/// Size and age limits a source file has to meet to be copied. Directories are never filtered.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct FileFilter {
+    pub(crate) min_size: Option<u64>,
+    pub(crate) max_size: Option<u64>,
+    /// Only files modified at or after this time are copied
+    pub(crate) newer_than: Option<SystemTime>,
+    /// Only files modified before this time are copied
+    pub(crate) older_than: Option<SystemTime>,
+}
+
+impl FileFilter {
+    /// Return the first filter the file with the given metadata fails, or `None` if it passes.
+    pub(crate) fn check(&self, metadata: &Metadata) -> Option<FilterKind> {
+        if self
+            .min_size
+            .is_some_and(|min_size| metadata.len < min_size)
+        {
+            Some(FilterKind::MinSize)
+        } else if self
+            .max_size
+            .is_some_and(|max_size| metadata.len > max_size)
+        {
+            Some(FilterKind::MaxSize)
+        } else if self
+            .newer_than
+            .is_some_and(|newer_than| metadata.modified < newer_than)
+        {
+            Some(FilterKind::NewerThan)
+        } else if self
+            .older_than
+            .is_some_and(|older_than| metadata.modified >= older_than)
+        {
+            Some(FilterKind::OlderThan)
+        } else {
+            None
+        }
+    }
+}
+
+/// Split `value` into its leading number and the unit following it.
+fn split_number(value: &str) -> (&str, &str) {
+    let end = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    (&value[..end], value[end..].trim())
+}
+
+/// Parse a size like `500`, `10K`, `1.5GiB` or `2MB`. All units are binary, so `1K` is 1024 bytes.
+pub(crate) fn parse_size(value: &str) -> Result<u64, String> {
+    let (number, unit) = split_number(value.trim());
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{value}'"))?;
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        _ => return Err(format!("unknown size unit '{unit}' in '{value}'")),
+    };
+    Ok((number * multiplier as f64).round() as u64)
+}
+
+/// Parse a duration like `90s`, `15m`, `12h`, `7d` or `2w`.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let (number, unit) = split_number(value);
+    let number: u64 = number.parse().ok()?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number.checked_mul(seconds)?))
+}
+
+/// Parse a UTC date like `2024-05-01`, optionally followed by a time like `13:30` or
+/// `13:30:15`, separated by a space or `T`.
+fn parse_date(value: &str) -> Option<SystemTime> {
+    let (date, time) = match value.split_once(['T', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (value, None),
+    };
+    let mut date_parts = date.splitn(3, '-');
+    let year: i64 = date_parts.next()?.parse().ok()?;
+    let month: u32 = date_parts.next()?.parse().ok()?;
+    let day: u32 = date_parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+        return None;
+    }
+
+    let mut second_of_day = 0;
+    if let Some(time) = time {
+        let mut time_parts = time.splitn(3, ':');
+        let hour: u32 = time_parts.next()?.parse().ok()?;
+        let minute: u32 = time_parts.next()?.parse().ok()?;
+        let second: u32 = match time_parts.next() {
+            Some(second) => second.parse().ok()?,
+            None => 0,
+        };
+        if hour > 23 || minute > 59 || second > 59 {
+            return None;
+        }
+        second_of_day = (hour * 3600 + minute * 60 + second) as i64;
+    }
+    Some(from_unix_seconds(
+        days_from_civil(year, month, day) * 86400 + second_of_day,
+    ))
+}
+
+/// Parse a point in time given either as a UTC date or as a duration before `now`.
+pub(crate) fn parse_time(value: &str, now: SystemTime) -> Result<SystemTime, String> {
+    let value = value.trim();
+    if let Some(duration) = parse_duration(value) {
+        return now
+            .checked_sub(duration)
+            .ok_or_else(|| format!("duration '{value}' is too long"));
+    }
+    parse_date(value).ok_or_else(|| {
+        format!("invalid date or duration '{value}', use e.g. 2024-05-01, 2024-05-01T13:30 or 7d")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::storage::EntryKind;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("500"), Ok(500));
+        assert_eq!(parse_size("10K"), Ok(10 * 1024));
+        assert_eq!(parse_size("1.5GiB"), Ok(3 * 512 * 1024 * 1024));
+        assert_eq!(parse_size("2 mb"), Ok(2 * 1024 * 1024));
+        assert!(parse_size("big").is_err());
+        assert!(parse_size("10X").is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        assert_eq!(
+            parse_time("2h", now),
+            Ok(now - Duration::from_secs(2 * 3600))
+        );
+        assert_eq!(
+            parse_time("1w", now),
+            Ok(now - Duration::from_secs(7 * 86400))
+        );
+        assert_eq!(
+            parse_time("2024-02-29", now),
+            Ok(UNIX_EPOCH + Duration::from_secs(19_782 * 86400))
+        );
+        assert_eq!(
+            parse_time("2024-02-29T01:02:03", now),
+            Ok(UNIX_EPOCH + Duration::from_secs(19_782 * 86400 + 3723))
+        );
+        assert!(parse_time("2024-13-01", now).is_err());
+        assert!(parse_time("2024-02-31", now).is_err());
+        assert!(parse_time("2023-02-29", now).is_err());
+        assert!(parse_time("2024-02-29", now).is_ok());
+        assert!(parse_time("2024-05-01T-1:30", now).is_err());
+        assert!(parse_time("2024-05-01T13:-5", now).is_err());
+        assert!(parse_time("yesterday", now).is_err());
+    }
+
+    #[test]
+    fn test_file_filter() {
+        let filter = FileFilter {
+            min_size: Some(10),
+            max_size: Some(100),
+            newer_than: Some(UNIX_EPOCH + Duration::from_secs(1_000)),
+            older_than: Some(UNIX_EPOCH + Duration::from_secs(2_000)),
+        };
+        let file = |len, modified| Metadata {
+            kind: EntryKind::File,
+            len,
+            modified: UNIX_EPOCH + Duration::from_secs(modified),
+            mode: 0o644,
+            rdev: 0,
+            link_id: None,
+        };
+
+        assert_eq!(filter.check(&file(50, 1_500)), None);
+        assert_eq!(filter.check(&file(5, 1_500)), Some(FilterKind::MinSize));
+        assert_eq!(filter.check(&file(500, 1_500)), Some(FilterKind::MaxSize));
+        assert_eq!(filter.check(&file(50, 500)), Some(FilterKind::NewerThan));
+        assert_eq!(filter.check(&file(50, 2_000)), Some(FilterKind::OlderThan));
+    }
+}
@@ -0,0 +1,212 @@
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use serde::Serialize;
+
+use crate::summary::RunSummary;
+
+/// When a hook is run.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HookEvent {
+    Pre,
+    FileCopied,
+    Post,
+}
+
+/// How a run ended, passed to the post hook.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RunStatus {
+    /// Everything was synced
+    Completed,
+    /// The run stopped on an error, or some entries failed
+    Failed,
+    /// The run stopped before changing anything, e.g. because of missing free space or a quit
+    /// answer
+    Aborted,
+}
+
+/// Counts of a finished run, passed to the post hook.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct HookCounts {
+    scanned_files: usize,
+    unchanged_files: usize,
+    new_files: usize,
+    updated_files: usize,
+    skipped_files: usize,
+    created_directories: usize,
+    copied_bytes: u64,
+    failures: usize,
+}
+
+impl From<&RunSummary> for HookCounts {
+    fn from(summary: &RunSummary) -> Self {
+        HookCounts {
+            scanned_files: summary.scanned_files,
+            unchanged_files: summary.unchanged_files,
+            new_files: summary.new_files,
+            updated_files: summary.updated_files,
+            skipped_files: summary.skipped.iter().map(|(_, count)| count).sum(),
+            created_directories: summary.created_directories,
+            copied_bytes: summary.copied_bytes,
+            failures: summary.failures,
+        }
+    }
+}
+
+/// What a hook is told about the run. It's written as a JSON object to the hook's stdin, and
+/// every field is also set as an environment variable named `UDIR_` and the upper case field
+/// name, e.g. `UDIR_FILE_TARGET`.
+#[derive(Debug, Serialize)]
+pub(crate) struct HookContext {
+    pub(crate) event: HookEvent,
+    pub(crate) source: PathBuf,
+    pub(crate) target: PathBuf,
+    /// The copied file, for `FileCopied`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) file_source: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) file_target: Option<PathBuf>,
+    /// How the run ended, for `Post`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) status: Option<RunStatus>,
+    /// The counts of the run, for `Post` after a run which got to copy files
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub(crate) counts: Option<HookCounts>,
+}
+
+impl HookContext {
+    pub(crate) fn new(event: HookEvent, source: PathBuf, target: PathBuf) -> Self {
+        HookContext {
+            event,
+            source,
+            target,
+            file_source: None,
+            file_target: None,
+            status: None,
+            counts: None,
+        }
+    }
+
+    fn environment(&self) -> io::Result<Vec<(String, String)>> {
+        let serde_json::Value::Object(fields) = serde_json::to_value(self)? else {
+            unreachable!("the context is serialized as an object");
+        };
+        Ok(fields
+            .into_iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    serde_json::Value::String(value) => value,
+                    value => value.to_string(),
+                };
+                (format!("UDIR_{}", name.to_uppercase()), value)
+            })
+            .collect())
+    }
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Run a hook command with the shell and wait for it. A hook which exits with a non-zero status
+/// counts as failed.
+pub(crate) fn run_hook(command: &str, context: &HookContext) -> io::Result<()> {
+    let mut child = shell_command(command)
+        .envs(context.environment()?)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let mut json = serde_json::to_vec(context)?;
+    json.push(b'\n');
+    match stdin.write_all(&json) {
+        // A hook which only uses the environment doesn't have to read its stdin
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+        result => result?,
+    }
+    drop(stdin);
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("hook {status}")))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    #[test]
+    fn test_run_hook() {
+        let test_dir_path = env::current_dir().unwrap().join("test_dir_hooks");
+        let _ = fs::remove_dir_all(&test_dir_path);
+        fs::create_dir(&test_dir_path).unwrap();
+        let output_path = test_dir_path.join("output");
+
+        let mut context = HookContext::new(
+            HookEvent::FileCopied,
+            PathBuf::from("/source"),
+            PathBuf::from("/target"),
+        );
+        context.file_source = Some(PathBuf::from("/source/a.txt"));
+        context.file_target = Some(PathBuf::from("/target/a.txt"));
+        run_hook(&format!("cat > '{}'", output_path.display()), &context).unwrap();
+        assert_eq!(
+            fs::read_to_string(&output_path).unwrap(),
+            "{\"event\":\"file_copied\",\"source\":\"/source\",\"target\":\"/target\",\
+             \"file_source\":\"/source/a.txt\",\"file_target\":\"/target/a.txt\"}\n"
+        );
+
+        let mut context = HookContext::new(
+            HookEvent::Post,
+            PathBuf::from("/source"),
+            PathBuf::from("/target"),
+        );
+        context.status = Some(RunStatus::Completed);
+        context.counts = Some(HookCounts::from(&RunSummary {
+            new_files: 2,
+            skipped: vec![("declined".to_string(), 3)],
+            ..Default::default()
+        }));
+        run_hook(
+            &format!(
+                "echo \"$UDIR_EVENT $UDIR_STATUS $UDIR_TARGET $UDIR_NEW_FILES $UDIR_SKIPPED_FILES\" > '{}'",
+                output_path.display()
+            ),
+            &context,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(&output_path).unwrap(),
+            "post completed /target 2 3\n"
+        );
+
+        // Without counts after an aborted run
+        context.status = Some(RunStatus::Aborted);
+        context.counts = None;
+        run_hook(&format!("cat > '{}'", output_path.display()), &context).unwrap();
+        assert_eq!(
+            fs::read_to_string(&output_path).unwrap(),
+            "{\"event\":\"post\",\"source\":\"/source\",\"target\":\"/target\",\"status\":\"aborted\"}\n"
+        );
+
+        assert!(run_hook("exit 3", &context).is_err());
+
+        fs::remove_dir_all(test_dir_path).unwrap();
+    }
+}
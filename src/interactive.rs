@@ -0,0 +1,177 @@
+use std::io::{self, BufRead, Write};
+
+use crate::file_handling::FileToCopy;
+use crate::progress::{format_bytes, format_time};
+use crate::storage::{EntryKind, Storage};
+
+/// Answer to a single overwrite question.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Answer {
+    Yes,
+    No,
+    All,
+    None,
+    Quit,
+}
+
+impl Answer {
+    fn parse(input: &str) -> Option<Self> {
+        match input.trim().to_lowercase().as_str() {
+            "y" | "yes" => Some(Answer::Yes),
+            "n" | "no" => Some(Answer::No),
+            "a" | "all" => Some(Answer::All),
+            "o" | "none" => Some(Answer::None),
+            "q" | "quit" => Some(Answer::Quit),
+            _ => None,
+        }
+    }
+}
+
+/// Ask on `output` whether each file which replaces an existing target file should be copied,
+/// reading the answers from `input`. Files which don't replace anything are kept without asking.
+/// Returns the files to copy, or `None` if the user quit or `input` ended.
+pub(crate) fn confirm_overwrites<S: Storage, T: Storage>(
+    source_storage: &S,
+    target_storage: &T,
+    files: Vec<FileToCopy>,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> io::Result<Option<Vec<FileToCopy>>> {
+    let mut confirmed = Vec::new();
+    // Set by "all" or "none" for every remaining file
+    let mut remaining_answer = None;
+
+    for file in files {
+        if target_storage.entry_kind(&file.target)? != Some(EntryKind::File) {
+            confirmed.push(file);
+            continue;
+        }
+
+        let answer = match remaining_answer {
+            Some(answer) => answer,
+            None => {
+                let source_metadata = source_storage.metadata(&file.source)?;
+                let target_metadata = target_storage.metadata(&file.target)?;
+                writeln!(output, "Overwrite {}?", file.target.display())?;
+                writeln!(
+                    output,
+                    "    source: {}, modified {}",
+                    format_bytes(source_metadata.len),
+                    format_time(source_metadata.modified)
+                )?;
+                writeln!(
+                    output,
+                    "    target: {}, modified {}",
+                    format_bytes(target_metadata.len),
+                    format_time(target_metadata.modified)
+                )?;
+                loop {
+                    write!(output, "[y]es, [n]o, [a]ll, n[o]ne, [q]uit: ")?;
+                    output.flush()?;
+                    let mut line = String::new();
+                    if input.read_line(&mut line)? == 0 {
+                        break Answer::Quit;
+                    }
+                    if let Some(answer) = Answer::parse(&line) {
+                        break answer;
+                    }
+                }
+            }
+        };
+
+        match answer {
+            Answer::Yes => confirmed.push(file),
+            Answer::No => {}
+            Answer::All => {
+                remaining_answer = Some(Answer::Yes);
+                confirmed.push(file);
+            }
+            Answer::None => remaining_answer = Some(Answer::No),
+            Answer::Quit => return Ok(None),
+        }
+    }
+    Ok(Some(confirmed))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn setup() -> (MemoryStorage, MemoryStorage, Vec<FileToCopy>) {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let source = MemoryStorage::new();
+        let target = MemoryStorage::new();
+        target.add_dir("/target");
+        let mut files = Vec::new();
+        for name in ["a", "b", "c", "new"] {
+            source.add_file(Path::new("/source").join(name), b"new", modified);
+            if name != "new" {
+                target.add_file(Path::new("/target").join(name), b"old", modified);
+            }
+            files.push(FileToCopy {
+                source: PathBuf::from("/source").join(name),
+                target: PathBuf::from("/target").join(name),
+                size: 3,
+            });
+        }
+        (source, target, files)
+    }
+
+    fn names(files: Option<Vec<FileToCopy>>) -> Option<Vec<PathBuf>> {
+        files.map(|files| files.into_iter().map(|file| file.target).collect())
+    }
+
+    #[test]
+    fn test_confirm_overwrites() {
+        let (source, target, files) = setup();
+        let mut output = Vec::new();
+
+        // An invalid answer is asked again, "none" answers all remaining questions
+        let confirmed = confirm_overwrites(
+            &source,
+            &target,
+            files.clone(),
+            &mut &b"y\nmaybe\no\n"[..],
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            names(confirmed),
+            Some(vec![
+                PathBuf::from("/target/a"),
+                PathBuf::from("/target/new")
+            ])
+        );
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Overwrite /target/a?"));
+        assert!(output.contains("source: 3 B, modified 1970-01-01 00:16:40 UTC"));
+        assert_eq!(output.matches("Overwrite").count(), 2);
+
+        let confirmed = confirm_overwrites(
+            &source,
+            &target,
+            files.clone(),
+            &mut &b"n\nall\n"[..],
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(confirmed.unwrap().len(), 3);
+
+        // Quitting and the end of the input both stop without copying anything
+        for input in [&b"y\nq\n"[..], &b"y\n"[..]] {
+            let confirmed = confirm_overwrites(
+                &source,
+                &target,
+                files.clone(),
+                &mut &input[..],
+                &mut Vec::new(),
+            )
+            .unwrap();
+            assert!(confirmed.is_none());
+        }
+    }
+}
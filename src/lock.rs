@@ -0,0 +1,236 @@
+use std::fmt;
+use std::fs::{self, File, TryLockError};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::progress::format_time;
+use crate::time::{from_unix_seconds, unix_seconds};
+
+/// Name of the lock file in a target directory.
+const LOCK_FILE_NAME: &str = ".udir.lock";
+
+/// The run holding a lock, as recorded in the lock file.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct LockOwner {
+    pid: u32,
+    host: String,
+    started: SystemTime,
+}
+
+impl LockOwner {
+    fn current() -> Self {
+        LockOwner {
+            pid: std::process::id(),
+            host: host_name(),
+            started: SystemTime::now(),
+        }
+    }
+
+    fn parse(content: &str) -> Option<Self> {
+        let mut lines = content.lines();
+        let pid = lines.next()?.parse().ok()?;
+        let host = lines.next()?.to_string();
+        let started = from_unix_seconds(lines.next()?.parse().ok()?);
+        Some(LockOwner { pid, host, started })
+    }
+
+    fn to_content(&self) -> String {
+        format!(
+            "{}\n{}\n{}\n",
+            self.pid,
+            self.host,
+            unix_seconds(self.started)
+        )
+    }
+}
+
+impl fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PID {} on {}, started {}",
+            self.pid,
+            self.host,
+            format_time(self.started)
+        )
+    }
+}
+
+#[cfg(unix)]
+fn host_name() -> String {
+    let mut buffer = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length, one byte is kept for the terminator
+    if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len() - 1) } != 0 {
+        return "unknown".to_string();
+    }
+    let len = buffer
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn host_name() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Advisory lock on a target, held while the value lives. Only other udir runs respect it.
+///
+/// The lock itself is an OS file lock on the lock file, which the OS releases when the process
+/// ends, so a lock file left behind by a crashed run is simply locked again. The file records
+/// the owner for the message shown to other runs.
+#[derive(Debug)]
+pub(crate) struct TargetLock {
+    path: PathBuf,
+    /// Open for as long as the lock is held, closing it releases the OS lock
+    file: Option<File>,
+}
+
+impl TargetLock {
+    /// Path of the lock file for a target directory or, next to it, for a target archive.
+    pub(crate) fn lock_path(target: &Path, is_archive: bool) -> PathBuf {
+        if is_archive {
+            let name = target.file_name().unwrap_or_default().to_string_lossy();
+            target.with_file_name(format!(".{name}{LOCK_FILE_NAME}"))
+        } else {
+            target.join(LOCK_FILE_NAME)
+        }
+    }
+
+    /// Take the lock at `path`. If it's held by another run, its owner is returned instead, or
+    /// `None` if the lock file can't be read. A lock file left behind by a run which doesn't
+    /// exist anymore is taken over.
+    pub(crate) fn try_acquire(path: &Path) -> io::Result<Result<TargetLock, Option<LockOwner>>> {
+        loop {
+            let mut options = File::options();
+            options.read(true).write(true).create(true).truncate(false);
+            // Without FILE_SHARE_DELETE, the file can't be removed while another run has it open
+            #[cfg(windows)]
+            std::os::windows::fs::OpenOptionsExt::share_mode(&mut options, 0x1 | 0x2);
+            let mut file = options.open(path)?;
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => {
+                    let mut content = String::new();
+                    let owner = match file.read_to_string(&mut content) {
+                        Ok(_) => LockOwner::parse(&content),
+                        Err(_) => None,
+                    };
+                    return Ok(Err(owner));
+                }
+                Err(TryLockError::Error(e)) => return Err(e),
+            }
+
+            // The previous owner may have removed the file between opening and locking it, then
+            // the lock has to be taken on the file which is at `path` now
+            if !is_file_at(&file, path)? {
+                continue;
+            }
+            file.set_len(0)?;
+            file.rewind()?;
+            file.write_all(LockOwner::current().to_content().as_bytes())?;
+            return Ok(Ok(TargetLock {
+                path: path.to_path_buf(),
+                file: Some(file),
+            }));
+        }
+    }
+}
+
+/// Return whether `file` is the file at `path`.
+#[cfg(unix)]
+fn is_file_at(file: &File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = file.metadata()?;
+    match fs::metadata(path) {
+        Ok(current) => Ok(current.dev() == metadata.dev() && current.ino() == metadata.ino()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Return whether `file` is the file at `path`, which it always is because it can't be removed
+/// while it's open.
+#[cfg(not(unix))]
+fn is_file_at(_file: &File, _path: &Path) -> io::Result<bool> {
+    Ok(true)
+}
+
+impl Drop for TargetLock {
+    fn drop(&mut self) {
+        // On Unix the file is removed while it's still locked, so a run which opened it in the
+        // meantime notices it's gone. Elsewhere it can't be removed while it's open, which also
+        // keeps it for a run which opened it in the meantime.
+        if cfg!(not(unix)) {
+            self.file.take();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn test_target_lock() {
+        let test_dir_path = env::current_dir().unwrap().join("test_dir_lock");
+        let _ = fs::remove_dir_all(&test_dir_path);
+        fs::create_dir(&test_dir_path).unwrap();
+        let lock_path = TargetLock::lock_path(&test_dir_path, false);
+
+        let lock = TargetLock::try_acquire(&lock_path).unwrap().unwrap();
+        let owner = TargetLock::try_acquire(&lock_path)
+            .unwrap()
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(owner.pid, std::process::id());
+        assert_eq!(owner.host, host_name());
+
+        drop(lock);
+        assert!(!lock_path.exists());
+
+        // A lock file nobody holds is taken over, whatever it contains
+        fs::write(&lock_path, "garbage").unwrap();
+        let lock = TargetLock::try_acquire(&lock_path).unwrap().unwrap();
+        // Another run sees the lock before the owner is written
+        fs::write(&lock_path, "").unwrap();
+        assert!(TargetLock::try_acquire(&lock_path)
+            .unwrap()
+            .unwrap_err()
+            .is_none());
+        drop(lock);
+
+        fs::remove_dir_all(test_dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_stale_target_lock() {
+        let test_dir_path = env::current_dir().unwrap().join("test_dir_stale_lock");
+        let _ = fs::remove_dir_all(&test_dir_path);
+        fs::create_dir(&test_dir_path).unwrap();
+        let lock_path = TargetLock::lock_path(&test_dir_path, false);
+
+        // Left behind by a run which is gone, nobody holds the OS lock anymore
+        fs::write(&lock_path, format!("{}\n{}\n0\n", i32::MAX, host_name())).unwrap();
+        let lock = TargetLock::try_acquire(&lock_path).unwrap().unwrap();
+        let content = fs::read_to_string(&lock_path).unwrap();
+        assert_eq!(LockOwner::parse(&content).unwrap().pid, std::process::id());
+
+        drop(lock);
+        fs::remove_dir_all(test_dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_lock_path_of_archive() {
+        assert_eq!(
+            TargetLock::lock_path(Path::new("/backups/data.tar"), true),
+            PathBuf::from("/backups/.data.tar.udir.lock")
+        );
+    }
+}
@@ -1,9 +1,43 @@
+mod compat;
+mod failed_list;
 mod file_handling;
+mod filter;
+mod hooks;
+mod interactive;
+mod lock;
+mod output;
+mod path_list;
+mod progress;
+mod retry;
+mod storage;
+mod summary;
+mod time;
 
-use clap::Parser;
+use clap::{ArgAction, Parser};
 use std::collections::HashSet;
 use std::env;
-use std::path::{Path, PathBuf};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use compat::TargetFsCompat;
+use file_handling::{
+    CopyOrder, FileToCopy, FilesAndDirectories, FillOrder, NameCollisionAction, ScanOptions,
+    TypeConflict,
+};
+use filter::FileFilter;
+use hooks::{HookContext, HookCounts, HookEvent, RunStatus};
+use lock::TargetLock;
+use output::{Output, Verbosity};
+use progress::format_bytes;
+use retry::RetryPolicy;
+use storage::{ArchiveFormat, Endpoint, EntryKind, Storage};
+use summary::RunSummary;
+
+/// How often a locked target is checked again with --wait.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Parser)]
 #[
@@ -11,57 +45,968 @@ use std::path::{Path, PathBuf};
         version,
         about = "A simple utility for recursively updating a target directory from a source directory based on its 'modified' timestamp.",
         long_about = None,
+        after_help = "Hooks are only set with --pre-hook, --post-hook and --on-file-copied, they can't be configured in a profile.",
     )
 ]
 struct Cli {
-    #[arg(help = "Source directory to copy from")]
+    #[arg(help = "Source directory or archive (.tar, .tar.zst, .zip) to copy from")]
     source: PathBuf,
 
-    #[arg(help = "Target directory to copy to")]
+    #[arg(help = "Target directory or archive (.tar, .tar.zst, .zip) to copy to")]
     target: PathBuf,
 
+    #[arg(help = "Only sync these paths, relative to SOURCE and TARGET, instead of everything")]
+    paths: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["paths", "retry_from"],
+        help = "Only sync the paths listed in FILE (- for stdin), relative to SOURCE and separated by newlines or NUL, without descending into listed directories"
+    )]
+    files_from: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Only descend N directory levels below SOURCE, 0 only syncs the files directly inside it"
+    )]
+    max_depth: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = filter::parse_size,
+        help = "Skip files smaller than SIZE, e.g. 100K (units are binary)"
+    )]
+    min_size: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = filter::parse_size,
+        help = "Skip files larger than SIZE, e.g. 2G (units are binary)"
+    )]
+    max_size: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "DATE|DURATION",
+        value_parser = parse_time_arg,
+        help = "Skip files modified before a UTC date (2024-05-01, 2024-05-01T13:30) or a duration ago (30m, 12h, 7d, 2w)"
+    )]
+    newer_than: Option<SystemTime>,
+
+    #[arg(
+        long,
+        value_name = "DATE|DURATION",
+        value_parser = parse_time_arg,
+        help = "Skip files modified at or after a UTC date or a duration ago"
+    )]
+    older_than: Option<SystemTime>,
+
     #[arg(long, help = "Add directories to skip (absolute or relative to SOURCE)", num_args = 1..)]
     skip_dir: Option<Vec<PathBuf>>,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Skip files and directories with this name at any depth, e.g. node_modules",
+        num_args = 1..
+    )]
+    skip_name: Vec<OsString>,
+
+    #[arg(
+        long,
+        help = "Allow TARGET inside SOURCE or the other way around, TARGET is then excluded from SOURCE"
+    )]
+    allow_nested: bool,
+
+    #[arg(short, long, help = "Only print failures", conflicts_with = "verbose")]
+    quiet: bool,
+
+    #[arg(
+        short,
+        long,
+        action = ArgAction::Count,
+        help = "Print more details, repeat (-vv) to also print the plan before syncing"
+    )]
+    verbose: u8,
+
+    #[arg(
+        long,
+        help = "Remove target files which are directories in SOURCE and vice versa, instead of skipping them"
+    )]
+    replace_type_conflicts: bool,
+
+    #[arg(
+        long,
+        help = "Only create directories which end up containing at least one copied file"
+    )]
+    prune_empty_dirs: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        help = "Retry directories and files which failed with a transient error up to N times at the end of the run"
+    )]
+    retries: u32,
+
+    #[arg(
+        long,
+        value_name = "DELAY",
+        default_value = "1s",
+        value_parser = retry::parse_delay,
+        help = "Wait before the first retry, doubled for every further one, e.g. 500ms or 2s"
+    )]
+    retry_delay: Duration,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write the directories and files which failed, with the reason, to FILE as JSON Lines"
+    )]
+    failed_list: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Only retry the entries of a --failed-list FILE instead of scanning SOURCE"
+    )]
+    retry_from: Option<PathBuf>,
+
+    #[arg(
+        short,
+        long,
+        conflicts_with = "quiet",
+        help = "Ask before overwriting each existing TARGET file"
+    )]
+    interactive: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        help = "Order in which files are copied"
+    )]
+    order: CopyOrder,
+
+    #[arg(long, help = "Remove empty directories from TARGET after syncing")]
+    delete_empty_target_dirs: bool,
+
+    #[arg(
+        long,
+        help = "Recreate FIFOs and device nodes in TARGET instead of skipping them. Device nodes need enough privileges"
+    )]
+    specials: bool,
+
+    #[arg(
+        long,
+        help = "Recreate files which are hard linked in SOURCE as hard links in TARGET instead of copying every name"
+    )]
+    hard_links: bool,
+
+    #[arg(
+        long,
+        help = "Remove each copied file from SOURCE once its copy in TARGET is verified"
+    )]
+    remove_source_files: bool,
+
+    #[arg(
+        long,
+        requires = "remove_source_files",
+        help = "Also remove SOURCE directories which are empty after removing the copied files"
+    )]
+    remove_empty_source_dirs: bool,
+
+    #[arg(
+        long,
+        help = "Wait until another run syncing into TARGET has finished instead of exiting"
+    )]
+    wait: bool,
+
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        help = "Run COMMAND before syncing, e.g. to mount TARGET. The run is aborted if it fails"
+    )]
+    pre_hook: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        help = "Run COMMAND after syncing, also when the run failed or was aborted"
+    )]
+    post_hook: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        help = "Run COMMAND for every copied file once all files are copied"
+    )]
+    on_file_copied: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "ORDER",
+        num_args = 0..=1,
+        default_missing_value = "plan",
+        help = "If TARGET is too small, copy as many files as fit, picked in ORDER [default: plan]"
+    )]
+    fill: Option<FillOrder>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        help = "What to do with SOURCE names which end up as the same TARGET name, because TARGET is case-insensitive or names are sanitized for it"
+    )]
+    name_collisions: NameCollisionAction,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        help = "Replace characters and names TARGET's filesystem doesn't allow with lookalikes"
+    )]
+    target_fs_compat: TargetFsCompat,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        value_parser = parse_modify_window,
+        help = "Treat modified timestamps within SECONDS as equal [default: detected from TARGET]"
+    )]
+    modify_window: Option<Duration>,
 }
 
-fn main_inner(source: PathBuf, target: PathBuf, directories_to_skip: HashSet<PathBuf>) {
-    let results = file_handling::get_files_and_directories(&source, &target, &directories_to_skip)
-        .expect("Files and directories could not be generated!");
-    let files = results.files;
+/// Parse `--newer-than` and `--older-than` relative to the time udir was started.
+fn parse_time_arg(value: &str) -> Result<SystemTime, String> {
+    filter::parse_time(value, SystemTime::now())
+}
+
+/// Parse `--modify-window`, which may have a fractional part.
+fn parse_modify_window(value: &str) -> Result<Duration, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("invalid number of seconds '{value}'"))
+}
+
+/// Use the timestamp granularity of the target directory as the modify window if it's coarser
+/// than what the storage backend promises, e.g. for a FAT formatted drive.
+fn detect_modify_window<T: Storage>(
+    target_storage: &T,
+    target: &Path,
+    output: &Output,
+) -> Duration {
+    match target_storage.probe_timestamp_granularity(target) {
+        Ok(granularity) if granularity > target_storage.timestamp_granularity() => {
+            if output.shows(Verbosity::Verbose) {
+                println!("Target stores modified timestamps with a granularity of {granularity:?}");
+            }
+            granularity
+        }
+        Ok(_) => Duration::ZERO,
+        Err(e) => {
+            if output.shows(Verbosity::Verbose) {
+                println!("Failed to detect the timestamp granularity of the target: {e}");
+            }
+            Duration::ZERO
+        }
+    }
+}
+
+/// Settings for the phases after the scan.
+#[derive(Debug, Default)]
+struct RunOptions {
+    delete_empty_target_dirs: bool,
+    fill: Option<FillOrder>,
+    retry: RetryPolicy,
+    /// Where to write the entries which failed
+    failed_list: Option<PathBuf>,
+    /// List of entries to retry instead of scanning
+    retry_from: Option<PathBuf>,
+    /// Ask before overwriting existing target files
+    interactive: bool,
+    order: CopyOrder,
+    /// Hook command run for every copied file
+    on_file_copied: Option<String>,
+}
+
+/// What a completed run did, for the steps after `main_inner`.
+#[derive(Debug)]
+struct RunOutcome {
+    summary: RunSummary,
+    copied_files: Vec<FileToCopy>,
+}
+
+/// Return whether the TARGET directory `path` has a counterpart in SOURCE. Sanitized names are
+/// mapped back to the source names they were created from.
+fn is_source_directory<S: Storage>(
+    source_storage: &S,
+    source: &Path,
+    target: &Path,
+    target_fs_compat: TargetFsCompat,
+    path: &Path,
+) -> bool {
+    let Ok(relative) = path.strip_prefix(target) else {
+        return false;
+    };
+    [
+        target_fs_compat.restore_path(relative),
+        relative.to_path_buf(),
+    ]
+    .iter()
+    .any(|relative| {
+        matches!(
+            source_storage.entry_kind(&source.join(relative)),
+            Ok(Some(EntryKind::Directory))
+        )
+    })
+}
+
+/// Return the type conflicts whose target entries have to make room. Directories are always
+/// created, but files left out by --fill or declined keep the target entry in their way.
+fn conflicts_to_resolve(
+    type_conflicts: &[TypeConflict],
+    planned_sources: &HashSet<&PathBuf>,
+) -> Vec<TypeConflict> {
+    type_conflicts
+        .iter()
+        .filter(|conflict| {
+            conflict.source_kind == EntryKind::Directory
+                || planned_sources.contains(&conflict.source)
+        })
+        .cloned()
+        .collect()
+}
+
+/// Make sure the files fit into the free space of the target. Returns the files to copy and the
+/// ones left out by `--fill`, or `None` if the run has to be aborted.
+fn fit_into_free_space<T: Storage>(
+    target_storage: &T,
+    target: &Path,
+    files: Vec<FileToCopy>,
+    fill: Option<FillOrder>,
+    output: &Output,
+) -> Option<(Vec<FileToCopy>, Vec<FileToCopy>)> {
+    let available = match target_storage.available_space(target) {
+        Ok(Some(available)) => available,
+        Ok(None) => return Some((files, Vec::new())),
+        Err(e) => {
+            if output.shows(Verbosity::Normal) {
+                println!("Failed to check the free space of the target, copying anyway: {e}");
+            }
+            return Some((files, Vec::new()));
+        }
+    };
+    let space_needed = file_handling::space_needed(target_storage, &files);
+    let total: u64 = space_needed.iter().sum();
+    if total <= available {
+        return Some((files, Vec::new()));
+    }
+    match fill {
+        Some(order) => Some(file_handling::select_files_that_fit(
+            files,
+            &space_needed,
+            available,
+            order,
+        )),
+        None => {
+            println!(
+                "Not enough free space on the target: {} needed, {} available. Use --fill to copy as many files as fit.",
+                format_bytes(total),
+                format_bytes(available)
+            );
+            None
+        }
+    }
+}
+
+/// Build the plan from a `--failed-list` file instead of scanning. All entries have to belong to
+/// the given SOURCE and TARGET. Directories which exist by now are left out.
+fn load_retry_list<T: Storage>(
+    target_storage: &T,
+    source: &Path,
+    target: &Path,
+    retry_from: &Path,
+) -> io::Result<FilesAndDirectories> {
+    let (directories, files) = failed_list::read_failed_list(retry_from)?;
+    let foreign = directories
+        .iter()
+        .map(|directory| &directory.path)
+        .chain(files.iter().map(|file| &file.target))
+        .find(|path| !path.starts_with(target))
+        .or_else(|| {
+            files
+                .iter()
+                .map(|file| &file.source)
+                .find(|path| !path.starts_with(source))
+        });
+    if let Some(foreign) = foreign {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is not part of the given source and target",
+                foreign.display()
+            ),
+        ));
+    }
+
+    let directories = directories
+        .into_iter()
+        .filter(|directory| {
+            !matches!(
+                target_storage.entry_kind(&directory.path),
+                Ok(Some(EntryKind::Directory))
+            )
+        })
+        .collect();
+    Ok(FilesAndDirectories {
+        files,
+        directories,
+        ..Default::default()
+    })
+}
+
+fn main_inner<S: Storage, T: Storage>(
+    source_storage: &S,
+    target_storage: &T,
+    source: PathBuf,
+    target: PathBuf,
+    options: ScanOptions,
+    run_options: &RunOptions,
+    output: &Output,
+) -> Result<RunOutcome, RunStatus> {
+    let start = Instant::now();
+    let results = match &run_options.retry_from {
+        Some(retry_from) => match load_retry_list(target_storage, &source, &target, retry_from) {
+            Ok(results) => results,
+            Err(e) => {
+                println!("Failed to read {}: {e}", retry_from.display());
+                return Err(RunStatus::Failed);
+            }
+        },
+        None => match file_handling::get_files_and_directories(
+            source_storage,
+            target_storage,
+            &source,
+            &target,
+            &options,
+        ) {
+            Ok(results) => results,
+            Err(e) => {
+                println!("Failed to scan {}: {e}", source.display());
+                return Err(RunStatus::Failed);
+            }
+        },
+    };
+    let mut files = results.files;
+    // Sorted before anything picks files, so --fill follows --order as well
+    file_handling::sort_files(source_storage, &mut files, run_options.order);
     let directories = results.directories;
+    let type_conflicts = results.type_conflicts;
+    let filtered = results.filtered;
+    let name_collisions = results.name_collisions;
+    let updated_targets = results.updated_targets;
+    let specials = results.specials;
+    let skipped_specials = results.skipped_specials;
+    let hard_links = results.hard_links;
+    let mut summary = RunSummary {
+        scanned_files: results.scanned_files,
+        unchanged_files: results.unchanged_files,
+        ..Default::default()
+    };
 
-    let failed_directories = file_handling::create_directories(&directories);
-    let failed_files = file_handling::copy_files(&files);
+    if !type_conflicts.is_empty()
+        && !options.replace_type_conflicts
+        && output.shows(Verbosity::Normal)
+    {
+        println!("Skipped because of type conflicts (use --replace-type-conflicts to replace):");
+        for conflict in &type_conflicts {
+            println!(
+                "    {} is a {} in SOURCE but a {} in TARGET",
+                conflict.target.display(),
+                conflict.source_kind,
+                conflict.target_kind
+            );
+        }
+    }
 
-    if !failed_files.is_empty() {
-        println!("Failed to create directories:");
-        for directory in failed_directories {
+    if !name_collisions.is_empty() && output.shows(Verbosity::Normal) {
+        println!("Names which collide on the case-insensitive target:");
+        for collision in &name_collisions {
+            match &collision.renamed_to {
+                Some(renamed_to) => println!(
+                    "    {} collides with {}, copied as {}",
+                    collision.source.display(),
+                    collision.collides_with.display(),
+                    renamed_to.display()
+                ),
+                None => println!(
+                    "    {} collides with {}, skipped (use --name-collisions rename to copy it)",
+                    collision.source.display(),
+                    collision.collides_with.display()
+                ),
+            }
+        }
+    }
+
+    if !skipped_specials.is_empty() && output.shows(Verbosity::Normal) {
+        if options.specials {
+            println!("Skipped special files:");
+        } else {
+            println!("Skipped special files (use --specials to recreate FIFOs and device nodes):");
+        }
+        for special in &skipped_specials {
+            println!("    {} is a {}", special.source.display(), special.kind);
+        }
+    }
+
+    if output.shows(Verbosity::Debug) {
+        println!("Directories to create: {}", directories.len());
+        for directory in &directories {
             println!("    {}", directory.path.display());
         }
+        println!(
+            "Files to copy: {} ({})",
+            files.len(),
+            format_bytes(files.iter().map(|file| file.size).sum())
+        );
+        for file in &files {
+            println!("    {} -> {}", file.source.display(), file.target.display());
+        }
+    }
+
+    let planned_targets: HashSet<PathBuf> = files.iter().map(|file| file.target.clone()).collect();
+    let (files, files_not_fitting) =
+        fit_into_free_space(target_storage, &target, files, run_options.fill, output)
+            .ok_or(RunStatus::Aborted)?;
+
+    let files_before_confirmation = files.len();
+    let files = if run_options.interactive {
+        match interactive::confirm_overwrites(
+            source_storage,
+            target_storage,
+            files,
+            &mut io::stdin().lock(),
+            &mut io::stdout(),
+        ) {
+            Ok(Some(files)) => files,
+            Ok(None) => {
+                println!("Quit, nothing was changed");
+                return Err(RunStatus::Aborted);
+            }
+            Err(e) => {
+                println!("Failed to ask for confirmation: {e}");
+                return Err(RunStatus::Failed);
+            }
+        }
+    } else {
+        files
+    };
+
+    let declined_files = files_before_confirmation - files.len();
+
+    // A link would share the content its original was supposed to get, so the links of originals
+    // left out by --fill or a declined overwrite are left out as well
+    let copied_targets: HashSet<&PathBuf> = files.iter().map(|file| &file.target).collect();
+    let (hard_links, left_out_links): (Vec<_>, Vec<_>) = hard_links.into_iter().partition(|link| {
+        !planned_targets.contains(&link.original) || copied_targets.contains(&link.original)
+    });
+
+    let failed_conflicts = if options.replace_type_conflicts {
+        let planned_sources = files
+            .iter()
+            .map(|file| &file.source)
+            .chain(specials.iter().map(|special| &special.source))
+            .chain(hard_links.iter().map(|link| &link.source))
+            .collect();
+        let type_conflicts = conflicts_to_resolve(&type_conflicts, &planned_sources);
+        file_handling::resolve_type_conflicts(target_storage, &type_conflicts, output)
+    } else {
+        Vec::new()
+    };
+    let mut failed_directories =
+        file_handling::create_directories(target_storage, &directories, output);
+    let failed_specials =
+        file_handling::create_specials(source_storage, target_storage, &specials, output);
+    let mut failed_files =
+        file_handling::copy_files(source_storage, target_storage, &files, output);
+
+    let mut recovered_directories = Vec::new();
+    let mut recovered_files = Vec::new();
+    if run_options.retry.retries > 0 {
+        (recovered_directories, failed_directories) = file_handling::retry_directories(
+            target_storage,
+            failed_directories,
+            &run_options.retry,
+            output,
+        );
+        (recovered_files, failed_files) = file_handling::retry_files(
+            source_storage,
+            target_storage,
+            failed_files,
+            &recovered_directories,
+            &run_options.retry,
+            output,
+        );
+    }
+
+    // The originals have to be copied first. Links which can't be created are copied instead.
+    let (linked_files, unlinked_files) =
+        file_handling::create_hard_links(target_storage, &hard_links, output);
+    failed_files.extend(file_handling::copy_files(
+        source_storage,
+        target_storage,
+        &unlinked_files,
+        output,
+    ));
+
+    let failed_sources: HashSet<_> = failed_files
+        .iter()
+        .map(|failure| &failure.entry.source)
+        .collect();
+    // Linked files count as copied, their sources can be removed just the same
+    let copied_files: Vec<_> = files
+        .iter()
+        .chain(&linked_files)
+        .chain(&unlinked_files)
+        .filter(|file| !failed_sources.contains(&file.source))
+        .collect();
+
+    if let Some(on_file_copied) = &run_options.on_file_copied {
+        for file in &copied_files {
+            let mut context =
+                HookContext::new(HookEvent::FileCopied, source.clone(), target.clone());
+            context.file_source = Some(file.source.clone());
+            context.file_target = Some(file.target.clone());
+            if let Err(e) = hooks::run_hook(on_file_copied, &context) {
+                println!("File copied hook failed for {}: {e}", file.source.display());
+            }
+        }
+    }
+
+    let failed_empty_directories = if run_options.delete_empty_target_dirs {
+        // The target counterparts of skipped directories are not part of the sync, and neither
+        // is SOURCE itself when it's nested inside TARGET. Directories created in this run are
+        // kept like every other one which exists in SOURCE, only orphans are removed.
+        let directories_to_keep: HashSet<_> = options
+            .directories_to_skip
+            .iter()
+            .filter_map(|directory| directory.strip_prefix(&source).ok())
+            .map(|relative| target.join(options.target_fs_compat.sanitize_path(relative)))
+            .chain(source.starts_with(&target).then(|| source.clone()))
+            .chain(directories.iter().map(|directory| directory.path.clone()))
+            .collect();
+        let keep = |path: &Path| {
+            directories_to_keep.contains(path)
+                || is_source_directory(
+                    source_storage,
+                    &source,
+                    &target,
+                    options.target_fs_compat,
+                    path,
+                )
+        };
+        file_handling::delete_empty_directories(target_storage, &target, &keep, output)
+    } else {
+        Vec::new()
+    };
+
+    if !files_not_fitting.is_empty() {
+        println!(
+            "Skipped because they don't fit into the free space of the target: {} files ({})",
+            files_not_fitting.len(),
+            format_bytes(files_not_fitting.iter().map(|file| file.size).sum())
+        );
+        if output.shows(Verbosity::Verbose) {
+            for file in &files_not_fitting {
+                println!("    {}", file.source.display());
+            }
+        }
+    }
+
+    if !failed_conflicts.is_empty() {
+        println!("Failed to remove conflicting target entries:");
+        for conflict in &failed_conflicts {
+            println!("    {}", conflict.target.display());
+        }
+    }
+
+    if !(recovered_directories.is_empty() && recovered_files.is_empty())
+        && output.shows(Verbosity::Normal)
+    {
+        println!(
+            "Recovered after retrying: {} directories, {} files",
+            recovered_directories.len(),
+            recovered_files.len()
+        );
+    }
+
+    if !failed_directories.is_empty() {
+        println!("Failed to create directories:");
+        for failure in &failed_directories {
+            println!("    {}: {}", failure.entry.path.display(), failure.error);
+        }
+    }
+
+    if !failed_specials.is_empty() {
+        println!("Failed to create special files:");
+        for failure in &failed_specials {
+            println!("    {}: {}", failure.entry.target.display(), failure.error);
+        }
     }
 
     if !failed_files.is_empty() {
         println!("Failed to copy files:");
-        for file in failed_files {
-            println!("    {}", file.source.display());
+        for failure in &failed_files {
+            println!("    {}: {}", failure.entry.source.display(), failure.error);
+        }
+    }
+
+    if !failed_empty_directories.is_empty() {
+        println!("Failed to remove empty directories:");
+        for directory in &failed_empty_directories {
+            println!("    {}", directory.display());
+        }
+    }
+
+    if let Some(failed_list) = &run_options.failed_list {
+        match failed_list::write_failed_list(failed_list, &failed_directories, &failed_files) {
+            Ok(()) => {
+                if !(failed_directories.is_empty() && failed_files.is_empty()) {
+                    println!(
+                        "Failed entries written to {}, use --retry-from to retry them",
+                        failed_list.display()
+                    );
+                }
+            }
+            Err(e) => println!("Failed to write {}: {e}", failed_list.display()),
+        }
+    }
+
+    let linked_targets: HashSet<_> = linked_files.iter().map(|file| &file.target).collect();
+    for file in &copied_files {
+        if updated_targets.contains(&file.target) {
+            summary.updated_files += 1;
+        } else {
+            summary.new_files += 1;
+        }
+        // A link shares the data of its original, nothing was copied for it
+        if !linked_targets.contains(&file.target) {
+            summary.copied_bytes += file.size;
+        }
+    }
+    summary.skipped = filtered
+        .into_iter()
+        .map(|(filter, count)| (filter.to_string(), count))
+        .collect();
+    if !options.replace_type_conflicts {
+        summary
+            .skipped
+            .push(("type conflicts".to_string(), type_conflicts.len()));
+    }
+    summary.skipped.extend([
+        (
+            "name collisions".to_string(),
+            name_collisions
+                .iter()
+                .filter(|collision| collision.renamed_to.is_none())
+                .count(),
+        ),
+        ("no free space".to_string(), files_not_fitting.len()),
+        ("declined".to_string(), declined_files),
+        (
+            "hard links of left out files".to_string(),
+            left_out_links.len(),
+        ),
+        ("special files".to_string(), skipped_specials.len()),
+    ]);
+    summary.created_directories = directories.len() - failed_directories.len();
+    summary.failures = failed_conflicts.len()
+        + failed_directories.len()
+        + failed_specials.len()
+        + failed_files.len()
+        + failed_empty_directories.len();
+    summary.elapsed = start.elapsed();
+
+    Ok(RunOutcome {
+        summary,
+        copied_files: copied_files.into_iter().cloned().collect(),
+    })
+}
+
+/// Check whether `--hard-links` can have an effect, and warn if hard linked files will be copied
+/// as separate files instead.
+fn hard_links_supported<T: Storage>(target_storage: &T, target: &Path, output: &Output) -> bool {
+    let warn = output.shows(Verbosity::Normal);
+    if cfg!(not(unix)) {
+        if warn {
+            println!("--hard-links has no effect on this platform, hard linked files are copied");
+        }
+        return false;
+    }
+    match target_storage.supports_hard_links(target) {
+        Ok(true) => true,
+        Ok(false) => {
+            if warn {
+                println!(
+                    "Target {} doesn't support hard links, hard linked files are copied",
+                    target.display()
+                );
+            }
+            false
+        }
+        Err(e) => {
+            if warn {
+                println!("Failed to check whether the target supports hard links, hard linked files are copied: {e}");
+            }
+            false
         }
     }
 }
 
+/// Check that every sub-path is relative, stays inside SOURCE and exists there. Returns a
+/// description of the first invalid path.
+fn validate_sub_paths<S: Storage>(
+    source_storage: &S,
+    source: &Path,
+    sub_paths: &[PathBuf],
+) -> Result<(), String> {
+    for sub_path in sub_paths {
+        if !sub_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(format!(
+                "Path {} must be relative to SOURCE and must not contain '..'",
+                sub_path.display()
+            ));
+        }
+        if !matches!(
+            source_storage.entry_kind(&source.join(sub_path)),
+            Ok(Some(_))
+        ) {
+            return Err(format!(
+                "Path {} does not exist in SOURCE",
+                sub_path.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// How SOURCE and TARGET overlap after resolving symlinks.
+#[derive(Debug, PartialEq)]
+enum Overlap {
+    Identical,
+    TargetInSource,
+    SourceInTarget,
+}
+
+/// Compare the canonical SOURCE and TARGET paths.
+fn find_overlap(source: &Path, target: &Path) -> Option<Overlap> {
+    if source == target {
+        Some(Overlap::Identical)
+    } else if target.starts_with(source) {
+        Some(Overlap::TargetInSource)
+    } else if source.starts_with(target) {
+        Some(Overlap::SourceInTarget)
+    } else {
+        None
+    }
+}
+
+/// Resolve all symlinks and relative components of `path`. A path which doesn't exist yet, like
+/// a new target archive, is resolved through its parent.
+fn canonicalize(path: &Path) -> io::Result<PathBuf> {
+    match fs::canonicalize(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => Ok(fs::canonicalize(parent)?.join(name)),
+            _ => Err(e),
+        },
+        result => result,
+    }
+}
+
+/// Turn the paths read by `--files-from` into sub-paths of SOURCE. Absolute paths inside SOURCE
+/// are made relative. Paths outside of SOURCE and paths which don't exist, e.g. deleted files
+/// in `git diff --name-only` output, are left out with a warning.
+fn prepare_listed_paths<S: Storage>(
+    source_storage: &S,
+    source: &Path,
+    paths: Vec<PathBuf>,
+    output: &Output,
+) -> Vec<PathBuf> {
+    let warn = output.shows(Verbosity::Normal);
+    let mut sub_paths = Vec::new();
+    for path in paths {
+        let relative = path.strip_prefix(source).unwrap_or(&path);
+        let sub_path: PathBuf = relative
+            .components()
+            .filter(|component| *component != Component::CurDir)
+            .collect();
+        if !sub_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            if warn {
+                println!("Warning: {} is outside of SOURCE, skipped", path.display());
+            }
+        } else if sub_path.as_os_str().is_empty() {
+            // SOURCE itself has nothing to sync without descending into it
+        } else if matches!(
+            source_storage.entry_kind(&source.join(&sub_path)),
+            Ok(Some(_))
+        ) {
+            sub_paths.push(sub_path);
+        } else if warn {
+            println!(
+                "Warning: {} does not exist in SOURCE, skipped",
+                path.display()
+            );
+        }
+    }
+    sub_paths
+}
+
 /// Extracts the directories to skip from the provided `skip_dir` argument and returns them as a `HashSet<PathBuf>`.
-/// Only directories that exist are added to the returned HashSet.
-fn extract_skipped_directories(
+/// Only directories that exist are added to the returned HashSet, a warning is printed for all others.
+fn extract_skipped_directories<S: Storage>(
+    source_storage: &S,
     source: &Path,
     skip_dirs: &Option<Vec<PathBuf>>,
+    output: &Output,
 ) -> HashSet<PathBuf> {
+    let warn = output.shows(Verbosity::Normal);
     let mut skipped_directories = HashSet::new();
     if let Some(skip_dirs) = skip_dirs {
         for skip_dir in skip_dirs {
             // This makes sure that the path is always either added to the source path or that it's
             // absolute
             let skip_dir_path = source.join(skip_dir);
-            if skip_dir_path.is_dir() {
-                skipped_directories.insert(skip_dir_path);
+            match source_storage.entry_kind(&skip_dir_path) {
+                Ok(Some(EntryKind::Directory)) => {
+                    skipped_directories.insert(skip_dir_path);
+                }
+                _ if !warn => {}
+                Ok(Some(_)) => println!(
+                    "Warning: directory to skip {} is not a directory, use --skip-name to skip files",
+                    skip_dir_path.display()
+                ),
+                Ok(None) => println!(
+                    "Warning: directory to skip {} does not exist",
+                    skip_dir_path.display()
+                ),
+                Err(e) => println!(
+                    "Warning: directory to skip {} cannot be checked: {e}",
+                    skip_dir_path.display()
+                ),
             }
         }
     }
@@ -70,6 +1015,7 @@ fn extract_skipped_directories(
 
 fn main() {
     let cli = Cli::parse();
+    let output = Output::new(Verbosity::from_flags(cli.quiet, cli.verbose));
 
     let source;
     let target;
@@ -82,39 +1028,372 @@ fn main() {
                 return;
             }
         };
-        source = cwd.join(cli.source);
-        target = cwd.join(cli.target);
+        source = cwd.join(&cli.source);
+        target = cwd.join(&cli.target);
     } else {
-        source = cli.source;
-        target = cli.target;
+        source = cli.source.clone();
+        target = cli.target.clone();
     }
 
+    // Run before anything is checked, so the hook can make SOURCE or TARGET available
+    if let Some(pre_hook) = &cli.pre_hook {
+        let context = HookContext::new(HookEvent::Pre, source.clone(), target.clone());
+        if let Err(e) = hooks::run_hook(pre_hook, &context) {
+            println!("Pre hook failed, nothing was synced: {e}");
+            return;
+        }
+    }
+
+    let result = sync(&cli, source.clone(), target.clone(), &output);
+
+    // The post hook runs however the run ended, so it can clean up after the pre hook
+    if let Some(post_hook) = &cli.post_hook {
+        let mut context = HookContext::new(HookEvent::Post, source, target);
+        context.status = Some(match &result {
+            Ok(summary) if summary.failures == 0 => RunStatus::Completed,
+            Ok(_) => RunStatus::Failed,
+            Err(status) => *status,
+        });
+        context.counts = result.as_ref().ok().map(HookCounts::from);
+        if let Err(e) = hooks::run_hook(post_hook, &context) {
+            println!("Post hook failed: {e}");
+        }
+    }
+}
+
+/// Everything a run does after the pre hook. Returns the summary of a run which got to copy
+/// files, or why it stopped before.
+fn sync(
+    cli: &Cli,
+    source: PathBuf,
+    target: PathBuf,
+    output: &Output,
+) -> Result<RunSummary, RunStatus> {
     // We cannot do anything if the source or target directories don't exist, so we check that early
-    // and exit if they are not directories.
-    if !source.is_dir() {
+    // and exit if they are not directories. Archives only have to exist when they are the source,
+    // a missing target archive is created.
+    if ArchiveFormat::from_path(&source).is_some() {
+        if !source.is_file() {
+            println!("Source {} is not an archive file", source.display());
+            return Err(RunStatus::Failed);
+        }
+        if cli.remove_source_files {
+            println!(
+                "Files can't be removed from source archive {}",
+                source.display()
+            );
+            return Err(RunStatus::Failed);
+        }
+    } else if !source.is_dir() {
         println!("Source {} is not a directory", source.display());
-        return;
+        return Err(RunStatus::Failed);
     }
 
-    if !target.is_dir() {
+    if ArchiveFormat::from_path(&target).is_some() {
+        if target.is_dir() || !target.parent().is_some_and(|parent| parent.is_dir()) {
+            println!("Target {} cannot be used as an archive", target.display());
+            return Err(RunStatus::Failed);
+        }
+    } else if !target.is_dir() {
         println!("Target {} is not a directory", target.display());
-        return;
+        return Err(RunStatus::Failed);
     }
 
-    println!("Source dir: {}", source.display());
-    println!("Target dir: {}", target.display());
+    let (canonical_source, canonical_target) = match (canonicalize(&source), canonicalize(&target))
+    {
+        (Ok(canonical_source), Ok(canonical_target)) => (canonical_source, canonical_target),
+        (Err(e), _) | (_, Err(e)) => {
+            println!("Failed to resolve source and target paths: {e}");
+            return Err(RunStatus::Failed);
+        }
+    };
+    let overlap = find_overlap(&canonical_source, &canonical_target);
+    match overlap {
+        Some(Overlap::Identical) => {
+            println!(
+                "Source {} and target {} are the same",
+                source.display(),
+                target.display()
+            );
+            return Err(RunStatus::Failed);
+        }
+        Some(Overlap::TargetInSource) if !cli.allow_nested => {
+            println!(
+                "Target {} is inside source {}, so later runs would copy the target into itself. Use --allow-nested to exclude it from the source.",
+                target.display(),
+                source.display()
+            );
+            return Err(RunStatus::Failed);
+        }
+        // An archive inside the target directory is only read, so it can't be overwritten
+        Some(Overlap::SourceInTarget)
+            if !cli.allow_nested && ArchiveFormat::from_path(&source).is_none() =>
+        {
+            println!(
+                "Source {} is inside target {}. Use --allow-nested to sync anyway.",
+                source.display(),
+                target.display()
+            );
+            return Err(RunStatus::Failed);
+        }
+        _ => {}
+    }
 
-    let directories_to_skip = extract_skipped_directories(&source, &cli.skip_dir);
-    if !directories_to_skip.is_empty() {
-        println!("Directories to skip:");
-        for directory in &directories_to_skip {
-            println!("    {}", directory.display());
+    // Taken before the target is read, so the run sees a consistent target and is released on
+    // every return below
+    let target_is_archive = ArchiveFormat::from_path(&target).is_some();
+    let lock_path = TargetLock::lock_path(&target, target_is_archive);
+    let mut waiting = false;
+    let _lock = loop {
+        match TargetLock::try_acquire(&lock_path) {
+            Ok(Ok(lock)) => break lock,
+            Ok(Err(owner)) => {
+                let owner = match owner {
+                    Some(owner) => format!("another run ({owner})"),
+                    None => "another run".to_string(),
+                };
+                if !cli.wait {
+                    println!(
+                        "Target {} is locked by {owner}. Use --wait to wait until it's released.",
+                        target.display()
+                    );
+                    return Err(RunStatus::Aborted);
+                }
+                if !waiting {
+                    println!("Waiting for {owner} to release target {}", target.display());
+                    waiting = true;
+                }
+                std::thread::sleep(LOCK_POLL_INTERVAL);
+            }
+            Err(e) => {
+                println!("Failed to lock target {}: {e}", target.display());
+                return Err(RunStatus::Failed);
+            }
+        }
+    };
+
+    let source_storage = match Endpoint::open(&source) {
+        Ok(storage) => storage,
+        Err(e) => {
+            println!("Failed to open source {}: {e}", source.display());
+            return Err(RunStatus::Failed);
+        }
+    };
+    let target_storage = match Endpoint::open(&target) {
+        Ok(storage) => storage,
+        Err(e) => {
+            println!("Failed to open target {}: {e}", target.display());
+            return Err(RunStatus::Failed);
+        }
+    };
+
+    let mut directories_to_skip =
+        extract_skipped_directories(&source_storage, &source, &cli.skip_dir, output);
+    if let Endpoint::Local(_) = source_storage {
+        // Skip paths may point into SOURCE through a symlink or `..`, so they are compared with
+        // the canonical SOURCE and then expressed relative to SOURCE as given
+        directories_to_skip = directories_to_skip
+            .into_iter()
+            .filter_map(|directory| {
+                match canonicalize(&directory).map(|canonical| {
+                    canonical
+                        .strip_prefix(&canonical_source)
+                        .map(|r| source.join(r))
+                }) {
+                    Ok(Ok(directory)) => Some(directory),
+                    _ => {
+                        println!(
+                            "Warning: directory to skip {} is not inside the source",
+                            directory.display()
+                        );
+                        None
+                    }
+                }
+            })
+            .collect();
+    }
+    if overlap == Some(Overlap::TargetInSource) {
+        let nested_target = source.join(canonical_target.strip_prefix(&canonical_source).unwrap());
+        // The lock file of an archive is next to it rather than inside, so it's skipped as well
+        if target_is_archive {
+            if let Some(lock_name) = lock_path.file_name() {
+                directories_to_skip.insert(nested_target.with_file_name(lock_name));
+            }
+        }
+        directories_to_skip.insert(nested_target);
+    }
+    // A trailing separator or `.` makes no difference for the sub-paths
+    let mut sub_paths: Vec<PathBuf> = cli
+        .paths
+        .iter()
+        .map(|path| {
+            path.components()
+                .filter(|c| *c != Component::CurDir)
+                .collect()
+        })
+        .collect();
+    if let Err(message) = validate_sub_paths(&source_storage, &source, &sub_paths) {
+        println!("{message}");
+        return Err(RunStatus::Failed);
+    }
+    if let Some(files_from) = &cli.files_from {
+        if cli.interactive && files_from == Path::new("-") {
+            println!(
+                "--interactive needs stdin for the answers, so --files-from can't read from it"
+            );
+            return Err(RunStatus::Failed);
+        }
+        match path_list::read_path_list(files_from) {
+            Ok(paths) => sub_paths = prepare_listed_paths(&source_storage, &source, paths, output),
+            Err(e) => {
+                println!("Failed to read paths from {}: {e}", files_from.display());
+                return Err(RunStatus::Failed);
+            }
+        }
+        // No sub-paths would mean the whole tree
+        if sub_paths.is_empty() {
+            println!("No paths to sync in {}", files_from.display());
+            return Err(RunStatus::Failed);
+        }
+    }
+
+    if output.shows(Verbosity::Normal) {
+        println!("Source dir: {}", source.display());
+        println!("Target dir: {}", target.display());
+
+        if !directories_to_skip.is_empty() {
+            println!("Directories to skip:");
+            for directory in &directories_to_skip {
+                println!("    {}", directory.display());
+            }
+        } else {
+            println!("No directories to skip");
+        }
+
+        if !cli.skip_name.is_empty() {
+            println!("Names to skip:");
+            for name in &cli.skip_name {
+                println!("    {}", name.to_string_lossy());
+            }
+        }
+
+        if let Some(files_from) = &cli.files_from {
+            println!(
+                "Paths to sync: {} from {}",
+                sub_paths.len(),
+                files_from.display()
+            );
+        } else if !sub_paths.is_empty() {
+            println!("Paths to sync:");
+            for sub_path in &sub_paths {
+                println!("    {}", sub_path.display());
+            }
+        }
+    }
+
+    let modify_window = cli
+        .modify_window
+        .unwrap_or_else(|| detect_modify_window(&target_storage, &target, output));
+
+    let hard_links = cli.hard_links && hard_links_supported(&target_storage, &target, output);
+
+    let outcome = main_inner(
+        &source_storage,
+        &target_storage,
+        source.clone(),
+        target.clone(),
+        ScanOptions {
+            directories_to_skip,
+            replace_type_conflicts: cli.replace_type_conflicts,
+            prune_empty_dirs: cli.prune_empty_dirs,
+            max_depth: cli.max_depth,
+            sub_paths,
+            list_only: cli.files_from.is_some(),
+            filter: FileFilter {
+                min_size: cli.min_size,
+                max_size: cli.max_size,
+                newer_than: cli.newer_than,
+                older_than: cli.older_than,
+            },
+            name_collisions: cli.name_collisions,
+            target_fs_compat: cli.target_fs_compat,
+            modify_window,
+            names_to_skip: cli.skip_name.iter().cloned().collect(),
+            specials: cli.specials,
+            hard_links,
+        },
+        &RunOptions {
+            delete_empty_target_dirs: cli.delete_empty_target_dirs,
+            fill: cli.fill,
+            retry: RetryPolicy {
+                retries: cli.retries,
+                delay: cli.retry_delay,
+            },
+            failed_list: cli.failed_list.clone(),
+            retry_from: cli.retry_from.clone(),
+            interactive: cli.interactive,
+            order: cli.order,
+            on_file_copied: cli.on_file_copied.clone(),
+        },
+        output,
+    );
+
+    let target_written = match target_storage.finish() {
+        Ok(written) => {
+            if written && output.shows(Verbosity::Normal) {
+                println!("Archive written: {}", target.display());
+            }
+            true
+        }
+        Err(e) => {
+            println!("Failed to write archive {}: {e}", target.display());
+            false
+        }
+    };
+
+    let RunOutcome {
+        mut summary,
+        copied_files,
+    } = outcome?;
+    if !target_written {
+        summary.failures += 1;
+    } else if cli.remove_source_files {
+        // Only done once an archive TARGET is written, before that the copies only exist in memory
+        let (removed_files, kept_files) = file_handling::remove_source_files(
+            &source_storage,
+            &target_storage,
+            copied_files,
+            modify_window,
+            output,
+        );
+        if !kept_files.is_empty() {
+            println!("Kept source files:");
+            for failure in &kept_files {
+                println!("    {}: {}", failure.entry.source.display(), failure.error);
+            }
+        }
+        summary.failures += kept_files.len();
+        if cli.remove_empty_source_dirs {
+            let failed_directories = file_handling::remove_empty_source_directories(
+                &source_storage,
+                &source,
+                &removed_files,
+                output,
+            );
+            if !failed_directories.is_empty() {
+                println!("Failed to remove empty source directories:");
+                for directory in &failed_directories {
+                    println!("    {}", directory.display());
+                }
+            }
+            summary.failures += failed_directories.len();
         }
-    } else {
-        println!("No directories to skip");
     }
 
-    main_inner(source, target, directories_to_skip);
+    if output.shows(Verbosity::Normal) {
+        println!("{summary}");
+    }
+    Ok(summary)
 }
 
 #[cfg(test)]
@@ -124,6 +1403,7 @@ mod tests {
     use std::time::Duration;
 
     use super::*;
+    use crate::storage::{LocalStorage, MemoryStorage};
 
     #[test]
     fn test_main_inner() {
@@ -160,7 +1440,7 @@ mod tests {
         let source_file_1 = source_dir_path.join("test_1.txt");
         let source_file_1_content = b"This is new text in file 1";
         fs::write(&target_file_1, b"This is old text in file 1").unwrap();
-        sleep(Duration::from_nanos(1)); // waiting so the source file is newer
+        sleep(Duration::from_millis(20)); // waiting so the source file is newer
         fs::write(&source_file_1, source_file_1_content).unwrap();
 
         // Write files that should stay the same
@@ -169,6 +1449,13 @@ mod tests {
         let source_file_2_content = b"This is unchanged text in file 2";
         fs::write(&target_file_2, source_file_2_content).unwrap();
         fs::copy(&target_file_2, &source_file_2).unwrap();
+        // fs::copy only keeps the timestamp on some platforms
+        LocalStorage
+            .set_modified(
+                &source_file_2,
+                fs::metadata(&target_file_2).unwrap().modified().unwrap(),
+            )
+            .unwrap();
         assert_eq!(
             fs::metadata(&source_file_2).unwrap().modified().unwrap(),
             fs::metadata(&target_file_2).unwrap().modified().unwrap(),
@@ -180,6 +1467,13 @@ mod tests {
         let source_file_3_content = b"This is unchanged text in file 3";
         fs::write(&target_file_3, source_file_3_content).unwrap();
         fs::copy(&target_file_3, &source_file_3).unwrap();
+        // fs::copy only keeps the timestamp on some platforms
+        LocalStorage
+            .set_modified(
+                &source_file_3,
+                fs::metadata(&target_file_3).unwrap().modified().unwrap(),
+            )
+            .unwrap();
         assert_eq!(
             fs::metadata(&source_file_3).unwrap().modified().unwrap(),
             fs::metadata(&target_file_3).unwrap().modified().unwrap(),
@@ -190,7 +1484,7 @@ mod tests {
         let source_file_4 = source_subdir_1_path.join("test_4.txt");
         let source_file_4_content = b"This is new text in file 4";
         fs::write(&target_file_4, b"This is old text in file 4").unwrap();
-        sleep(Duration::from_nanos(1)); // waiting so the source file is newer
+        sleep(Duration::from_millis(20)); // waiting so the source file is newer
         fs::write(&source_file_4, source_file_4_content).unwrap();
 
         // Write a file that should be created in subdirectory 1
@@ -212,10 +1506,15 @@ mod tests {
 
         // Run the tested function
         main_inner(
+            &LocalStorage,
+            &LocalStorage,
             source_dir_path.clone(),
             target_dir_path.clone(),
-            HashSet::new(),
-        );
+            ScanOptions::default(),
+            &RunOptions::default(),
+            &Output::new(Verbosity::Normal),
+        )
+        .unwrap();
 
         // Verify directory structure
         assert!(
@@ -308,10 +1607,108 @@ mod tests {
         fs::remove_dir_all(test_dir_path).unwrap();
     }
 
+    #[test]
+    fn test_conflicts_to_resolve() {
+        let conflict = |name: &str, source_kind| TypeConflict {
+            source: PathBuf::from("/source").join(name),
+            target: PathBuf::from("/target").join(name),
+            source_kind,
+            target_kind: if source_kind == EntryKind::Directory {
+                EntryKind::File
+            } else {
+                EntryKind::Directory
+            },
+        };
+        let type_conflicts = [
+            conflict("dir", EntryKind::Directory),
+            conflict("copied", EntryKind::File),
+            conflict("left_out", EntryKind::File),
+        ];
+        let copied = PathBuf::from("/source/copied");
+        assert_eq!(
+            conflicts_to_resolve(&type_conflicts, &HashSet::from([&copied])),
+            type_conflicts[..2]
+        );
+    }
+
+    #[test]
+    fn test_is_source_directory() {
+        let storage = MemoryStorage::new();
+        storage.add_dir("/source/empty");
+        storage.add_dir("/source/a:b");
+        storage.add_file("/source/file", b"", SystemTime::UNIX_EPOCH);
+        let is_source_directory = |target_fs_compat, path: &str| {
+            is_source_directory(
+                &storage,
+                Path::new("/source"),
+                Path::new("/target"),
+                target_fs_compat,
+                Path::new(path),
+            )
+        };
+        assert!(is_source_directory(TargetFsCompat::Posix, "/target/empty"));
+        assert!(is_source_directory(
+            TargetFsCompat::Ntfs,
+            "/target/a\u{ff1a}b"
+        ));
+        assert!(!is_source_directory(
+            TargetFsCompat::Posix,
+            "/target/orphan"
+        ));
+        assert!(!is_source_directory(TargetFsCompat::Posix, "/target/file"));
+    }
+
+    #[test]
+    fn test_find_overlap() {
+        let data = Path::new("/data");
+        assert_eq!(find_overlap(data, data), Some(Overlap::Identical));
+        assert_eq!(
+            find_overlap(data, Path::new("/data/backup")),
+            Some(Overlap::TargetInSource)
+        );
+        assert_eq!(
+            find_overlap(Path::new("/data/backup"), data),
+            Some(Overlap::SourceInTarget)
+        );
+        // A common prefix of the names alone isn't nesting
+        assert_eq!(find_overlap(data, Path::new("/data-backup")), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_canonicalize_resolves_symlinks() {
+        let test_dir_path = env::current_dir().unwrap().join("test_dir_canonicalize");
+        let _ = fs::remove_dir_all(&test_dir_path);
+        fs::create_dir_all(test_dir_path.join("data")).unwrap();
+        std::os::unix::fs::symlink(test_dir_path.join("data"), test_dir_path.join("alias"))
+            .unwrap();
+
+        let canonical_data = canonicalize(&test_dir_path.join("data")).unwrap();
+        assert_eq!(
+            canonicalize(&test_dir_path.join("alias")).unwrap(),
+            canonical_data
+        );
+        // A target archive which doesn't exist yet
+        assert_eq!(
+            canonicalize(&test_dir_path.join("alias/new.tar")).unwrap(),
+            canonical_data.join("new.tar")
+        );
+
+        fs::remove_dir_all(test_dir_path).unwrap();
+    }
+
     #[test]
     fn test_extract_skipped_directories_receives_none() {
         let source = PathBuf::from("source");
-        assert_eq!(extract_skipped_directories(&source, &None), HashSet::new());
+        assert_eq!(
+            extract_skipped_directories(
+                &LocalStorage,
+                &source,
+                &None,
+                &Output::new(Verbosity::Normal)
+            ),
+            HashSet::new()
+        );
     }
 
     #[test]
@@ -348,7 +1745,12 @@ mod tests {
         ]);
 
         assert_eq!(
-            extract_skipped_directories(&test_dir_path, &Some(skip_dirs)),
+            extract_skipped_directories(
+                &LocalStorage,
+                &test_dir_path,
+                &Some(skip_dirs),
+                &Output::new(Verbosity::Normal)
+            ),
             result
         );
 
@@ -0,0 +1,71 @@
+use std::io::{self, IsTerminal};
+
+/// How much udir prints while it's running. Failures are always reported.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub(crate) enum Verbosity {
+    /// Only failures
+    Quiet,
+    /// Every created directory and copied file
+    Normal,
+    /// Additionally sizes and the reason of every failure as it happens
+    Verbose,
+    /// Additionally the complete plan before anything is changed
+    Debug,
+}
+
+impl Verbosity {
+    /// Map the `-q` flag and the number of `-v` flags to a verbosity level.
+    pub(crate) fn from_flags(quiet: bool, verbose: u8) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::Debug,
+        }
+    }
+}
+
+/// Output settings shared by all phases of a run.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Output {
+    pub(crate) verbosity: Verbosity,
+    /// Whether progress is redrawn in place. Only done on terminals, logs get plain lines.
+    pub(crate) live_progress: bool,
+}
+
+impl Output {
+    /// Create the output settings for stdout, detecting whether it's a terminal.
+    pub(crate) fn new(verbosity: Verbosity) -> Self {
+        Output {
+            verbosity,
+            live_progress: verbosity >= Verbosity::Normal && io::stdout().is_terminal(),
+        }
+    }
+
+    /// Return whether messages of the given level are printed.
+    pub(crate) fn shows(&self, level: Verbosity) -> bool {
+        self.verbosity >= level
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verbosity_from_flags() {
+        assert_eq!(Verbosity::from_flags(true, 0), Verbosity::Quiet);
+        assert_eq!(Verbosity::from_flags(false, 0), Verbosity::Normal);
+        assert_eq!(Verbosity::from_flags(false, 1), Verbosity::Verbose);
+        assert_eq!(Verbosity::from_flags(false, 2), Verbosity::Debug);
+        assert_eq!(Verbosity::from_flags(false, 5), Verbosity::Debug);
+    }
+
+    #[test]
+    fn test_quiet_output_has_no_live_progress() {
+        let output = Output::new(Verbosity::Quiet);
+        assert!(!output.live_progress);
+        assert!(!output.shows(Verbosity::Normal));
+        assert!(output.shows(Verbosity::Quiet));
+    }
+}
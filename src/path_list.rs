@@ -0,0 +1,68 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Read a list of paths from `path`, or from stdin if it's `-`.
+pub(crate) fn read_path_list(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut content = Vec::new();
+    if path == Path::new("-") {
+        io::stdin().lock().read_to_end(&mut content)?;
+    } else {
+        File::open(path)?.read_to_end(&mut content)?;
+    }
+    parse_path_list(&content)
+}
+
+/// Split a list of paths. Paths are separated by NUL characters if there are any, like the
+/// output of `find -print0`, and by line breaks otherwise. Empty entries are ignored.
+fn parse_path_list(content: &[u8]) -> io::Result<Vec<PathBuf>> {
+    let separator = if content.contains(&0) { 0 } else { b'\n' };
+    content
+        .split(|byte| *byte == separator)
+        .map(|entry| {
+            if separator == b'\n' {
+                entry.strip_suffix(b"\r").unwrap_or(entry)
+            } else {
+                entry
+            }
+        })
+        .filter(|entry| !entry.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> io::Result<PathBuf> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    Ok(PathBuf::from(OsStr::from_bytes(bytes)))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> io::Result<PathBuf> {
+    String::from_utf8(bytes.to_vec())
+        .map(PathBuf::from)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_path_list() {
+        assert_eq!(
+            parse_path_list(b"a.txt\r\ndir/b.txt\n\nc d.txt\n").unwrap(),
+            vec![
+                PathBuf::from("a.txt"),
+                PathBuf::from("dir/b.txt"),
+                PathBuf::from("c d.txt")
+            ]
+        );
+        // With NUL separators, line breaks are part of the names
+        assert_eq!(
+            parse_path_list(b"./a\nb\0c\0").unwrap(),
+            vec![PathBuf::from("./a\nb"), PathBuf::from("c")]
+        );
+    }
+}
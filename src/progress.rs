@@ -0,0 +1,370 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::time::{civil_from_days, unix_seconds};
+
+/// How often the progress line is redrawn while a file is being copied.
+const RENDER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How far back the current throughput looks, so the rate and ETA follow changes in speed.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(10);
+
+/// Format a number of bytes with a binary unit, e.g. `1.50 MiB`.
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024. && unit < UNITS.len() - 1 {
+        value /= 1024.;
+        unit += 1;
+    }
+    format!("{value:.2} {}", UNITS[unit])
+}
+
+/// Format a duration as `mm:ss`, or `h:mm:ss` once it's an hour or longer.
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 3600 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds % 3600 / 60,
+            seconds % 60
+        )
+    } else {
+        format!("{:02}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+/// Format a point in time as a UTC date and time, e.g. `2024-05-01 13:30:15 UTC`.
+pub(crate) fn format_time(time: SystemTime) -> String {
+    let seconds = unix_seconds(time);
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let second_of_day = seconds.rem_euclid(86400);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        second_of_day / 3600,
+        second_of_day % 3600 / 60,
+        second_of_day % 60
+    )
+}
+
+/// A single line which is redrawn in place on a terminal. When the output isn't a terminal,
+/// nothing is drawn and messages are printed as plain lines without carriage returns.
+#[derive(Debug)]
+pub(crate) struct StatusLine {
+    live: bool,
+    last_render: Option<Instant>,
+    last_width: usize,
+}
+
+impl StatusLine {
+    pub(crate) fn new(live: bool) -> Self {
+        StatusLine {
+            live,
+            last_render: None,
+            last_width: 0,
+        }
+    }
+
+    /// Return whether the line should be redrawn now. It's redrawn at most every
+    /// `RENDER_INTERVAL` unless `force` is set, and never when the output isn't a terminal.
+    pub(crate) fn is_due(&self, force: bool) -> bool {
+        self.live
+            && (force
+                || self
+                    .last_render
+                    .is_none_or(|last_render| last_render.elapsed() >= RENDER_INTERVAL))
+    }
+
+    /// Redraw the line in place.
+    pub(crate) fn draw(&mut self, line: &str) {
+        self.last_render = Some(Instant::now());
+        // Pad with spaces so nothing of a longer previous line is left over
+        print!("\r{line:<width$}", width = self.last_width);
+        self.last_width = line.len();
+        // Make sure it flushes immediately
+        io::stdout().flush().unwrap();
+    }
+
+    /// Print a message on its own line, replacing the status line.
+    pub(crate) fn println(&mut self, message: &str) {
+        if self.live {
+            println!("\r{message:<width$}", width = self.last_width);
+            self.last_width = 0;
+            self.last_render = None;
+        } else {
+            println!("{message}");
+        }
+    }
+
+    /// Leave the last drawn status line on screen and move to the next line.
+    pub(crate) fn finish(&mut self) {
+        if self.live && self.last_width > 0 {
+            println!();
+            self.last_width = 0;
+        }
+    }
+}
+
+/// Byte based progress of the copy phase, rendered as a single terminal line.
+#[derive(Debug)]
+pub(crate) struct ByteProgress {
+    total_bytes: u64,
+    total_files: usize,
+    /// Bytes actually copied
+    done_bytes: u64,
+    /// Expected bytes of finished files which weren't copied, e.g. because the file failed. They
+    /// are left out of the total rather than counted as done, which would show up as a burst
+    /// in the throughput.
+    skipped_bytes: u64,
+    done_files: usize,
+    file_bytes: u64,
+    file_len: u64,
+    started: Instant,
+    /// Time since `started` and `done_bytes` at every redraw within `THROUGHPUT_WINDOW`, oldest
+    /// first
+    samples: VecDeque<(Duration, u64)>,
+    status_line: StatusLine,
+}
+
+impl ByteProgress {
+    pub(crate) fn new(total_bytes: u64, total_files: usize, live: bool) -> Self {
+        ByteProgress {
+            total_bytes,
+            total_files,
+            done_bytes: 0,
+            skipped_bytes: 0,
+            done_files: 0,
+            file_bytes: 0,
+            file_len: 0,
+            started: Instant::now(),
+            samples: VecDeque::new(),
+            status_line: StatusLine::new(live),
+        }
+    }
+
+    /// Start tracking a new file of `len` bytes.
+    pub(crate) fn start_file(&mut self, len: u64) {
+        self.file_bytes = 0;
+        self.file_len = len;
+        self.render(true);
+    }
+
+    /// Start the current file over, e.g. for another attempt after a failed one. The bytes of
+    /// the earlier attempt don't count anymore.
+    pub(crate) fn restart_file(&mut self) {
+        self.done_bytes -= self.file_bytes;
+        self.file_bytes = 0;
+        self.render(true);
+    }
+
+    /// Record `bytes` more bytes of the current file as copied. Bytes beyond the expected size
+    /// of a file which grew since the scan aren't counted, so the total is never exceeded.
+    pub(crate) fn advance(&mut self, bytes: u64) {
+        let bytes = bytes.min(self.file_len.saturating_sub(self.file_bytes));
+        self.file_bytes += bytes;
+        self.done_bytes += bytes;
+        self.render(false);
+    }
+
+    /// Mark the current file as finished, whether it was copied successfully or not. Expected
+    /// bytes which weren't copied are left out of the total, so it stays reachable.
+    pub(crate) fn finish_file(&mut self) {
+        self.skipped_bytes += self.file_len.saturating_sub(self.file_bytes);
+        self.file_bytes = 0;
+        self.file_len = 0;
+        self.done_files += 1;
+    }
+
+    /// Remember the progress after `elapsed` time for the throughput. Samples older than
+    /// needed to cover `THROUGHPUT_WINDOW` are dropped.
+    fn record_sample(&mut self, elapsed: Duration) {
+        self.samples.push_back((elapsed, self.done_bytes));
+        while self
+            .samples
+            .get(1)
+            .is_some_and(|(time, _)| elapsed.saturating_sub(*time) >= THROUGHPUT_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes copied per second over the last `THROUGHPUT_WINDOW`, measured from the latest
+    /// sample at least that old. Before there is one, it's measured from the oldest sample or,
+    /// without any, from the start of the copy phase.
+    fn throughput(&self, elapsed: Duration) -> f64 {
+        let (since, bytes_before) = self
+            .samples
+            .iter()
+            .rev()
+            .find(|(time, _)| elapsed.saturating_sub(*time) >= THROUGHPUT_WINDOW)
+            .or(self.samples.front())
+            .copied()
+            .unwrap_or((Duration::ZERO, 0));
+        let seconds = elapsed.saturating_sub(since).as_secs_f64();
+        if seconds > 0. {
+            self.done_bytes.saturating_sub(bytes_before) as f64 / seconds
+        } else {
+            0.
+        }
+    }
+
+    /// Build the progress line for the state after `elapsed` time.
+    pub(crate) fn line(&self, elapsed: Duration) -> String {
+        let total_bytes = self.total_bytes.saturating_sub(self.skipped_bytes);
+        let percentage = if total_bytes > 0 {
+            self.done_bytes as f64 / total_bytes as f64 * 100.
+        } else {
+            self.done_files as f64 / self.total_files.max(1) as f64 * 100.
+        };
+        let throughput = self.throughput(elapsed);
+        let eta = if throughput > 0. {
+            let remaining = total_bytes.saturating_sub(self.done_bytes) as f64;
+            format_duration(Duration::from_secs_f64(remaining / throughput))
+        } else {
+            "--:--".to_string()
+        };
+        let mut line = format!(
+            "Copying files: {percentage:.2}% ({}/{}, {}/{} files) {}/s, ETA {eta}",
+            format_bytes(self.done_bytes),
+            format_bytes(total_bytes),
+            self.done_files,
+            self.total_files,
+            format_bytes(throughput as u64),
+        );
+        if self.file_len > 0 {
+            line.push_str(&format!(
+                " [current file {:.0}%]",
+                self.file_bytes as f64 / self.file_len as f64 * 100.
+            ));
+        }
+        line
+    }
+
+    /// Redraw the progress line in place, at most every `RENDER_INTERVAL` unless `force` is set.
+    pub(crate) fn render(&mut self, force: bool) {
+        if self.status_line.is_due(force) {
+            let elapsed = self.started.elapsed();
+            let line = self.line(elapsed);
+            self.status_line.draw(&line);
+            self.record_sample(elapsed);
+        }
+    }
+
+    /// Print a message on its own line, replacing the progress line.
+    pub(crate) fn println(&mut self, message: &str) {
+        self.status_line.println(message);
+    }
+
+    /// Draw the final state of the progress line and move to the next line.
+    pub(crate) fn finish(&mut self) {
+        self.render(true);
+        self.status_line.finish();
+    }
+}
+
+/// Reader which reports every chunk read to a `ByteProgress`.
+pub(crate) struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a mut ByteProgress,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    pub(crate) fn new(inner: R, progress: &'a mut ByteProgress) -> Self {
+        ProgressReader { inner, progress }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.progress.advance(read as u64);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.50 KiB");
+        assert_eq!(format_bytes(20 * 1024 * 1024 * 1024), "20.00 GiB");
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(0)), "00:00");
+        assert_eq!(format_duration(Duration::from_secs(75)), "01:15");
+        assert_eq!(format_duration(Duration::from_secs(3725)), "1:02:05");
+    }
+
+    #[test]
+    fn test_format_time() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(19_782 * 86400 + 3723);
+        assert_eq!(format_time(time), "2024-02-29 01:02:03 UTC");
+    }
+
+    #[test]
+    fn test_byte_progress_line() {
+        let mut progress = ByteProgress::new(4096, 2, false);
+        assert_eq!(
+            progress.line(Duration::ZERO),
+            "Copying files: 0.00% (0 B/4.00 KiB, 0/2 files) 0 B/s, ETA --:--"
+        );
+
+        // A single big file dominates the percentage, not the file count
+        progress.file_len = 3072;
+        progress.file_bytes = 1024;
+        progress.done_bytes = 1024;
+        assert_eq!(
+            progress.line(Duration::from_secs(1)),
+            "Copying files: 25.00% (1.00 KiB/4.00 KiB, 0/2 files) 1.00 KiB/s, ETA 00:03 [current file 33%]"
+        );
+
+        // Another attempt drops the bytes of the failed one
+        progress.restart_file();
+        assert_eq!(
+            progress.line(Duration::from_secs(1)),
+            "Copying files: 0.00% (0 B/4.00 KiB, 0/2 files) 0 B/s, ETA --:-- [current file 0%]"
+        );
+
+        // The bytes of a failed file are left out of the total instead of counting as done
+        progress.finish_file();
+        assert_eq!(
+            progress.line(Duration::from_secs(1)),
+            "Copying files: 0.00% (0 B/1.00 KiB, 1/2 files) 0 B/s, ETA --:--"
+        );
+
+        // A file which grew since the scan doesn't go past the total
+        progress.start_file(1024);
+        progress.advance(2048);
+        assert_eq!(
+            progress.line(Duration::from_secs(1)),
+            "Copying files: 100.00% (1.00 KiB/1.00 KiB, 1/2 files) 1.00 KiB/s, ETA 00:00 [current file 100%]"
+        );
+    }
+
+    #[test]
+    fn test_throughput_window() {
+        let mut progress = ByteProgress::new(1 << 20, 1, false);
+        progress.record_sample(Duration::ZERO);
+        progress.done_bytes = 100 * 1024;
+        progress.record_sample(Duration::from_secs(10));
+        assert_eq!(progress.throughput(Duration::from_secs(10)), 10. * 1024.);
+
+        // Slowing down shows in the rate instead of being averaged since the start
+        progress.done_bytes = 110 * 1024;
+        progress.record_sample(Duration::from_secs(20));
+        assert_eq!(progress.throughput(Duration::from_secs(20)), 1024.);
+        assert_eq!(progress.samples.len(), 2);
+    }
+}
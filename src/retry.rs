@@ -0,0 +1,139 @@
+use std::io;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Longest wait between two attempts, no matter how many retries were made.
+const MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// How often and how patiently failed operations are retried.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct RetryPolicy {
+    /// Number of retries after the first failure, 0 disables retrying
+    pub(crate) retries: u32,
+    /// Wait before the first retry, doubled for every following one
+    pub(crate) delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 0,
+            delay: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before retry number `retry`, starting at 0.
+    fn delay_before(&self, retry: u32) -> Duration {
+        self.delay.saturating_mul(1 << retry.min(16)).min(MAX_DELAY)
+    }
+
+    /// Run `operation` again until it succeeds, fails with an error which isn't transient or
+    /// the retries are used up. Returns the result of the last attempt.
+    pub(crate) fn retry<T>(&self, mut operation: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut retry = 0;
+        loop {
+            sleep(self.delay_before(retry));
+            match operation() {
+                Err(e) if is_transient(&e) && retry + 1 < self.retries => retry += 1,
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Return whether an operation which failed with `error` may succeed when it's tried again,
+/// e.g. because a file was locked or a network mount was briefly unavailable.
+pub(crate) fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ResourceBusy
+            | io::ErrorKind::StaleNetworkFileHandle
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::NetworkDown
+    )
+}
+
+/// Parse `--retry-delay`, given in seconds or with an `ms` or `s` suffix.
+pub(crate) fn parse_delay(value: &str) -> Result<Duration, String> {
+    let (number, scale) = match value.trim() {
+        value if value.ends_with("ms") => (&value[..value.len() - 2], 0.001),
+        value if value.ends_with('s') => (&value[..value.len() - 1], 1.),
+        value => (value, 1.),
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .and_then(|number| Duration::try_from_secs_f64(number * scale).ok())
+        .ok_or_else(|| format!("invalid delay '{value}', use e.g. 500ms or 2s"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_backoff() {
+        let policy = RetryPolicy {
+            retries: 30,
+            delay: Duration::from_millis(500),
+        };
+        assert_eq!(policy.delay_before(0), Duration::from_millis(500));
+        assert_eq!(policy.delay_before(3), Duration::from_secs(4));
+        assert_eq!(policy.delay_before(29), MAX_DELAY);
+    }
+
+    #[test]
+    fn test_retry() {
+        let policy = RetryPolicy {
+            retries: 3,
+            delay: Duration::ZERO,
+        };
+
+        // Transient errors are retried until the operation succeeds
+        let mut attempts = 0;
+        let result = policy.retry(|| {
+            attempts += 1;
+            if attempts < 3 {
+                Err(io::Error::from(io::ErrorKind::ResourceBusy))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        // ... but only as often as configured
+        let mut attempts = 0;
+        let result: io::Result<()> = policy.retry(|| {
+            attempts += 1;
+            Err(io::Error::from(io::ErrorKind::TimedOut))
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(attempts, 3);
+
+        // Permanent errors are not retried at all
+        let mut attempts = 0;
+        let result: io::Result<()> = policy.retry(|| {
+            attempts += 1;
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_parse_delay() {
+        assert_eq!(parse_delay("2"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_delay("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_delay("250ms"), Ok(Duration::from_millis(250)));
+        assert!(parse_delay("soon").is_err());
+    }
+}
@@ -0,0 +1,606 @@
+use std::cell::Cell;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use zip::extra_fields::ExtraField;
+use zip::write::FullFileOptions;
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+use super::{MemoryStorage, Metadata, Storage};
+use crate::time::{civil_from_days, days_from_civil, from_unix_seconds, unix_seconds};
+
+/// Header ID of the "extended timestamp" zip extra field, which stores the last modified
+/// timestamp as seconds since the Unix epoch instead of the local DOS time.
+const ZIP_EXTENDED_TIMESTAMP: u16 = 0x5455;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum ArchiveFormat {
+    Tar,
+    TarZstd,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Detect the archive format from the file extension of `path`.
+    pub(crate) fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        if name.ends_with(".tar") {
+            Some(ArchiveFormat::Tar)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(ArchiveFormat::TarZstd)
+        } else if name.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// Storage backend for a tar, zstd compressed tar or zip archive. The archive path itself acts
+/// as the root directory, so `backup.tar/docs/a.txt` is the entry `docs/a.txt` in `backup.tar`.
+///
+/// The whole archive is loaded into memory when it's opened and written back by `save`.
+#[derive(Debug)]
+pub(crate) struct ArchiveStorage {
+    path: PathBuf,
+    format: ArchiveFormat,
+    tree: MemoryStorage,
+    /// Tar entries the tree can't represent
+    preserved: Vec<PreservedEntry>,
+    changed: Cell<bool>,
+}
+
+/// Tar entry like a symlink, hard link or device node, which isn't part of the tree. It's
+/// written back unchanged, unless the sync put something else at its path.
+#[derive(Debug)]
+struct PreservedEntry {
+    /// Relative to the archive root
+    path: PathBuf,
+    header: tar::Header,
+    link_name: Option<PathBuf>,
+    data: Vec<u8>,
+}
+
+fn invalid_data(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+fn not_utf8(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "{} can't be stored in a zip archive, its name isn't valid UTF-8",
+            path.display()
+        ),
+    )
+}
+
+/// Turn the path of an archive entry into a relative path, rejecting anything which would
+/// escape the archive root.
+fn entry_path(path: &Path) -> io::Result<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            _ => {
+                return Err(invalid_data(format!(
+                    "Archive entry {} points outside of the archive",
+                    path.display()
+                )))
+            }
+        }
+    }
+    Ok(relative)
+}
+
+/// DOS timestamp of a zip entry. Zip has no time zone, so UTC is used for portability.
+fn zip_date_time(time: SystemTime) -> DateTime {
+    let seconds = unix_seconds(time);
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let second_of_day = seconds.rem_euclid(86400);
+    DateTime::from_date_and_time(
+        u16::try_from(year).unwrap_or(0),
+        month as u8,
+        day as u8,
+        (second_of_day / 3600) as u8,
+        (second_of_day % 3600 / 60) as u8,
+        (second_of_day % 60) as u8,
+    )
+    .unwrap_or_default()
+}
+
+fn zip_modified(file: &zip::read::ZipFile<'_, File>) -> SystemTime {
+    for field in file.extra_data_fields() {
+        if let ExtraField::ExtendedTimestamp(timestamp) = field {
+            if let Some(seconds) = timestamp.mod_time() {
+                return from_unix_seconds(seconds as i64);
+            }
+        }
+    }
+    match file.last_modified() {
+        Some(date_time) => from_unix_seconds(
+            days_from_civil(
+                date_time.year() as i64,
+                date_time.month() as u32,
+                date_time.day() as u32,
+            ) * 86400
+                + date_time.hour() as i64 * 3600
+                + date_time.minute() as i64 * 60
+                + date_time.second() as i64,
+        ),
+        None => UNIX_EPOCH,
+    }
+}
+
+impl ArchiveStorage {
+    /// Load the archive at `path`. If it doesn't exist, start with an empty archive which will be
+    /// created by `save`.
+    pub(crate) fn open(path: &Path, format: ArchiveFormat) -> io::Result<Self> {
+        let mut archive = ArchiveStorage {
+            path: path.to_path_buf(),
+            format,
+            tree: MemoryStorage::new(),
+            preserved: Vec::new(),
+            changed: Cell::new(false),
+        };
+        archive.tree.add_dir(path);
+
+        if fs::exists(path)? {
+            let file = File::open(path)?;
+            match format {
+                ArchiveFormat::Tar => archive.load_tar(BufReader::new(file))?,
+                ArchiveFormat::TarZstd => archive.load_tar(zstd::Decoder::new(file)?)?,
+                ArchiveFormat::Zip => archive.load_zip(file)?,
+            }
+        }
+        Ok(archive)
+    }
+
+    fn load_tar<R: Read>(&mut self, reader: R) -> io::Result<()> {
+        let mut tar = tar::Archive::new(reader);
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            let relative = entry_path(&entry.path()?)?;
+            let path = self.path.join(&relative);
+            let header = entry.header();
+            let modified = from_unix_seconds(header.mtime()? as i64);
+            let mode = header.mode()? & 0o7777;
+            match header.entry_type() {
+                tar::EntryType::Directory => self.tree.add_dir(&path),
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    let mut content = Vec::new();
+                    entry.read_to_end(&mut content)?;
+                    self.tree.add_file(&path, &content, modified);
+                }
+                // Only describes the archive, a new one is written without it
+                tar::EntryType::XGlobalHeader => continue,
+                // Links and special files can't be represented, they are kept as they are
+                _ => {
+                    let link_name = entry.link_name()?.map(|name| name.into_owned());
+                    let mut data = Vec::new();
+                    entry.read_to_end(&mut data)?;
+                    self.preserved.push(PreservedEntry {
+                        path: relative,
+                        header: entry.header().clone(),
+                        link_name,
+                        data,
+                    });
+                    continue;
+                }
+            }
+            self.tree.set_mode(&path, mode)?;
+            self.tree.set_modified(&path, modified)?;
+        }
+        Ok(())
+    }
+
+    fn load_zip(&self, file: File) -> io::Result<()> {
+        let mut zip = ZipArchive::new(file).map_err(invalid_data)?;
+        for i in 0..zip.len() {
+            let mut entry = zip.by_index(i).map_err(invalid_data)?;
+            let path = self.path.join(entry_path(Path::new(entry.name()))?);
+            let modified = zip_modified(&entry);
+            if entry.is_dir() {
+                self.tree.add_dir(&path);
+            } else {
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content)?;
+                self.tree.add_file(&path, &content, modified);
+            }
+            if let Some(mode) = entry.unix_mode() {
+                self.tree.set_mode(&path, mode & 0o7777)?;
+            }
+            self.tree.set_modified(&path, modified)?;
+        }
+        Ok(())
+    }
+
+    /// Write the archive back to disk if anything in it has changed. The new archive is written
+    /// next to the old one first and then moved over it, so an interrupted run leaves the
+    /// previous archive intact. Returns whether the archive was written.
+    pub(crate) fn save(&self) -> io::Result<bool> {
+        if !self.changed.get() {
+            return Ok(false);
+        }
+
+        let mut entries = Vec::new();
+        self.collect_entries(&self.path, &mut entries)?;
+
+        let file_name = self.path.file_name().unwrap().to_string_lossy();
+        let temporary_path = self.path.with_file_name(format!(".{file_name}.udir-tmp"));
+        let file = File::create(&temporary_path)?;
+        let result = match self.format {
+            ArchiveFormat::Tar => self
+                .save_tar(BufWriter::new(file), &entries)
+                .and_then(|writer| writer.into_inner().map_err(|e| e.into_error()))
+                .and_then(|file| file.sync_all()),
+            ArchiveFormat::TarZstd => zstd::Encoder::new(BufWriter::new(file), 0)
+                .and_then(|encoder| self.save_tar(encoder, &entries))
+                .and_then(|encoder| encoder.finish())
+                .and_then(|writer| writer.into_inner().map_err(|e| e.into_error()))
+                .and_then(|file| file.sync_all()),
+            ArchiveFormat::Zip => self.save_zip(file, &entries),
+        };
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temporary_path);
+            return Err(e);
+        }
+        fs::rename(&temporary_path, &self.path)?;
+        self.changed.set(false);
+        Ok(true)
+    }
+
+    /// Refuse names a zip archive can't store, so the entry fails when it's copied rather than
+    /// being mangled when the archive is written.
+    fn check_name(&self, path: &Path) -> io::Result<()> {
+        let relative = path.strip_prefix(&self.path).unwrap_or(path);
+        if self.format == ArchiveFormat::Zip && relative.to_str().is_none() {
+            return Err(not_utf8(relative));
+        }
+        Ok(())
+    }
+
+    /// Collect all entries below `directory` with their paths relative to the archive root,
+    /// parents before their children.
+    fn collect_entries(
+        &self,
+        directory: &Path,
+        entries: &mut Vec<(PathBuf, Metadata)>,
+    ) -> io::Result<()> {
+        for path in self.tree.list(directory)? {
+            let metadata = self.tree.metadata(&path)?;
+            let relative = path.strip_prefix(&self.path).unwrap().to_path_buf();
+            let is_dir = metadata.is_dir();
+            entries.push((relative, metadata));
+            if is_dir {
+                self.collect_entries(&path, entries)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn save_tar<W: Write>(&self, writer: W, entries: &[(PathBuf, Metadata)]) -> io::Result<W> {
+        let mut tar = tar::Builder::new(writer);
+        for (relative, metadata) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(metadata.mode);
+            header.set_mtime(unix_seconds(metadata.modified).max(0) as u64);
+            if metadata.is_dir() {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_size(0);
+                tar.append_data(&mut header, relative, io::empty())?;
+            } else {
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(metadata.len);
+                let content = self.tree.read(&self.path.join(relative))?;
+                tar.append_data(&mut header, relative, content)?;
+            }
+        }
+        for entry in &self.preserved {
+            if self
+                .tree
+                .entry_kind(&self.path.join(&entry.path))?
+                .is_some()
+            {
+                continue;
+            }
+            let mut header = entry.header.clone();
+            match &entry.link_name {
+                Some(link_name) => tar.append_link(&mut header, &entry.path, link_name)?,
+                None => tar.append_data(&mut header, &entry.path, &entry.data[..])?,
+            }
+        }
+        tar.into_inner()
+    }
+
+    fn save_zip(&self, file: File, entries: &[(PathBuf, Metadata)]) -> io::Result<()> {
+        let mut zip = ZipWriter::new(file);
+        for (relative, metadata) in entries {
+            // Zip entry names always use forward slashes
+            let name = relative
+                .components()
+                .map(|component| {
+                    component
+                        .as_os_str()
+                        .to_str()
+                        .ok_or_else(|| not_utf8(relative))
+                })
+                .collect::<io::Result<Vec<_>>>()?
+                .join("/");
+            let mut options = FullFileOptions::default()
+                .compression_method(CompressionMethod::Deflated)
+                .unix_permissions(metadata.mode)
+                .last_modified_time(zip_date_time(metadata.modified))
+                .large_file(metadata.len > u32::MAX as u64);
+            // The extended timestamp is read back as unsigned and only has 32 bits, outside of
+            // that range the DOS time has to do
+            if let Ok(seconds) = i32::try_from(unix_seconds(metadata.modified)) {
+                if seconds >= 0 {
+                    let mut timestamp = vec![1]; // Only the modification time is present
+                    timestamp.extend_from_slice(&seconds.to_le_bytes());
+                    options
+                        .add_extra_data(ZIP_EXTENDED_TIMESTAMP, timestamp, false)
+                        .map_err(invalid_data)?;
+                }
+            }
+            if metadata.is_dir() {
+                zip.add_directory(name, options).map_err(invalid_data)?;
+            } else {
+                zip.start_file(name, options).map_err(invalid_data)?;
+                io::copy(&mut self.tree.read(&self.path.join(relative))?, &mut zip)?;
+            }
+        }
+        zip.finish().map_err(invalid_data)?.sync_all()
+    }
+}
+
+impl Storage for ArchiveStorage {
+    fn list(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.tree.list(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.tree.metadata(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        self.tree.read(path)
+    }
+
+    fn write(&self, path: &Path, content: &mut dyn Read) -> io::Result<u64> {
+        self.check_name(path)?;
+        self.changed.set(true);
+        self.tree.write(path, content)
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.check_name(path)?;
+        self.changed.set(true);
+        self.tree.create_dir(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.changed.set(true);
+        self.tree.remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.changed.set(true);
+        self.tree.remove_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.changed.set(true);
+        self.tree.remove_dir_all(path)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.changed.set(true);
+        self.tree.set_mode(path, mode)
+    }
+
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        // Both tar headers and the zip extended timestamp only store whole seconds
+        self.changed.set(true);
+        self.tree
+            .set_modified(path, from_unix_seconds(unix_seconds(modified)))
+    }
+
+    fn timestamp_granularity(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn test_archive_format_from_path() {
+        assert_eq!(
+            ArchiveFormat::from_path(Path::new("backup.tar")),
+            Some(ArchiveFormat::Tar)
+        );
+        assert_eq!(
+            ArchiveFormat::from_path(Path::new("backup.TAR.ZST")),
+            Some(ArchiveFormat::TarZstd)
+        );
+        assert_eq!(
+            ArchiveFormat::from_path(Path::new("dir/backup.zip")),
+            Some(ArchiveFormat::Zip)
+        );
+        assert_eq!(ArchiveFormat::from_path(Path::new("backup.tar.gz")), None);
+        assert_eq!(ArchiveFormat::from_path(Path::new("backup")), None);
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let current_path = env::current_dir().unwrap();
+        let test_dir_path = current_path.join("test_dir_archive_round_trip");
+
+        match fs::remove_dir_all(&test_dir_path) {
+            Ok(_) => {}
+            Err(_) => println!("[INFO] Test dir couldn't be removed"),
+        };
+        fs::create_dir(&test_dir_path).unwrap();
+
+        let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        for (name, format) in [
+            ("backup.tar", ArchiveFormat::Tar),
+            ("backup.tar.zst", ArchiveFormat::TarZstd),
+            ("backup.zip", ArchiveFormat::Zip),
+        ] {
+            let archive_path = test_dir_path.join(name);
+            let archive = ArchiveStorage::open(&archive_path, format).unwrap();
+            assert!(!archive.save().unwrap(), "Unchanged archive isn't written");
+
+            archive.create_dir(&archive_path.join("subdir")).unwrap();
+            archive
+                .write(&archive_path.join("subdir/file.txt"), &mut &b"content"[..])
+                .unwrap();
+            archive
+                .set_modified(&archive_path.join("subdir/file.txt"), modified)
+                .unwrap();
+            archive
+                .set_mode(&archive_path.join("subdir/file.txt"), 0o600)
+                .unwrap();
+            assert!(archive.save().unwrap(), "Changed archive is written");
+
+            let reopened = ArchiveStorage::open(&archive_path, format).unwrap();
+            assert_eq!(
+                reopened.list(&archive_path).unwrap(),
+                vec![archive_path.join("subdir")],
+                "{name}"
+            );
+            let metadata = reopened
+                .metadata(&archive_path.join("subdir/file.txt"))
+                .unwrap();
+            assert_eq!(metadata.modified, modified, "{name}");
+            assert_eq!(metadata.mode, 0o600, "{name}");
+            let mut content = Vec::new();
+            reopened
+                .read(&archive_path.join("subdir/file.txt"))
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            assert_eq!(content, b"content", "{name}");
+        }
+
+        fs::remove_dir_all(test_dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_tar_links_are_kept() {
+        let current_path = env::current_dir().unwrap();
+        let test_dir_path = current_path.join("test_dir_archive_links");
+        let _ = fs::remove_dir_all(&test_dir_path);
+        fs::create_dir(&test_dir_path).unwrap();
+        let archive_path = test_dir_path.join("backup.tar");
+
+        let mut builder = tar::Builder::new(File::create(&archive_path).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(1);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "a", &b"a"[..]).unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder.append_link(&mut header, "link", "a").unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder.append_link(&mut header, "replaced", "a").unwrap();
+        builder.into_inner().unwrap().sync_all().unwrap();
+
+        let archive = ArchiveStorage::open(&archive_path, ArchiveFormat::Tar).unwrap();
+        archive
+            .write(&archive_path.join("n"), &mut &b"new"[..])
+            .unwrap();
+        archive
+            .write(&archive_path.join("replaced"), &mut &b"file"[..])
+            .unwrap();
+        assert!(archive.save().unwrap());
+
+        let mut tar = tar::Archive::new(File::open(&archive_path).unwrap());
+        let entries: Vec<_> = tar
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (
+                    entry.path().unwrap().into_owned(),
+                    entry.header().entry_type(),
+                    entry.link_name().unwrap().map(|name| name.into_owned()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (PathBuf::from("a"), tar::EntryType::Regular, None),
+                (PathBuf::from("n"), tar::EntryType::Regular, None),
+                (PathBuf::from("replaced"), tar::EntryType::Regular, None),
+                (
+                    PathBuf::from("link"),
+                    tar::EntryType::Symlink,
+                    Some(PathBuf::from("a"))
+                ),
+            ]
+        );
+
+        fs::remove_dir_all(test_dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_zip_names_and_late_timestamps() {
+        let current_path = env::current_dir().unwrap();
+        let test_dir_path = current_path.join("test_dir_archive_zip_names");
+        let _ = fs::remove_dir_all(&test_dir_path);
+        fs::create_dir(&test_dir_path).unwrap();
+        let archive_path = test_dir_path.join("backup.zip");
+
+        // Past the 32 bit extended timestamp, only the DOS time is stored
+        let modified = UNIX_EPOCH + Duration::from_secs(4_102_444_800);
+        let archive = ArchiveStorage::open(&archive_path, ArchiveFormat::Zip).unwrap();
+        archive
+            .write(&archive_path.join("late.txt"), &mut &b""[..])
+            .unwrap();
+        archive
+            .set_modified(&archive_path.join("late.txt"), modified)
+            .unwrap();
+        #[cfg(unix)]
+        {
+            use std::ffi::OsStr;
+            use std::os::unix::ffi::OsStrExt;
+
+            let name = OsStr::from_bytes(b"invalid\xff.txt");
+            let error = archive
+                .write(&archive_path.join(name), &mut &b""[..])
+                .unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(archive.save().unwrap());
+
+        let reopened = ArchiveStorage::open(&archive_path, ArchiveFormat::Zip).unwrap();
+        assert_eq!(
+            reopened.list(&archive_path).unwrap(),
+            vec![archive_path.join("late.txt")]
+        );
+        assert_eq!(
+            reopened
+                .metadata(&archive_path.join("late.txt"))
+                .unwrap()
+                .modified,
+            modified
+        );
+
+        fs::remove_dir_all(test_dir_path).unwrap();
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{EntryKind, Metadata, Storage};
+
+/// Storage backend for directories on a locally mounted filesystem.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct LocalStorage;
+
+/// Name of the file created in TARGET to find out its timestamp granularity.
+const GRANULARITY_PROBE_NAME: &str = ".udir-granularity-probe";
+
+/// Name of the file created in TARGET to find out whether it supports hard links.
+const HARD_LINK_PROBE_NAME: &str = ".udir-hard-link-probe";
+
+/// Granularities of common filesystems, from ext4 and APFS (1ns) over NTFS (100ns) and exFAT
+/// (10ms) to FAT (2s).
+const KNOWN_GRANULARITIES: [Duration; 6] = [
+    Duration::from_nanos(1),
+    Duration::from_nanos(100),
+    Duration::from_micros(1),
+    Duration::from_millis(10),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+];
+
+/// Swap the case of every letter in `name`, e.g. `ReadMe` becomes `rEADmE`.
+fn swap_case(name: &str) -> String {
+    name.chars()
+        .flat_map(|c| {
+            if c.is_lowercase() {
+                c.to_uppercase().collect::<Vec<_>>()
+            } else {
+                c.to_lowercase().collect()
+            }
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(unix)]
+fn kind_and_rdev_of(metadata: &fs::Metadata) -> (EntryKind, u64) {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        EntryKind::Directory
+    } else if file_type.is_fifo() {
+        EntryKind::Fifo
+    } else if file_type.is_socket() {
+        EntryKind::Socket
+    } else if file_type.is_block_device() {
+        EntryKind::BlockDevice
+    } else if file_type.is_char_device() {
+        EntryKind::CharDevice
+    } else {
+        EntryKind::File
+    };
+    let rdev = if matches!(kind, EntryKind::BlockDevice | EntryKind::CharDevice) {
+        metadata.rdev()
+    } else {
+        0
+    };
+    (kind, rdev)
+}
+
+#[cfg(unix)]
+fn link_id_of(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.is_file() && metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn link_id_of(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(not(unix))]
+fn kind_and_rdev_of(metadata: &fs::Metadata) -> (EntryKind, u64) {
+    if metadata.is_dir() {
+        (EntryKind::Directory, 0)
+    } else {
+        (EntryKind::File, 0)
+    }
+}
+
+#[cfg(not(unix))]
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+impl Storage for LocalStorage {
+    fn list(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(path)? {
+            paths.push(entry?.path());
+        }
+        Ok(paths)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = fs::metadata(path)?;
+        let (kind, rdev) = kind_and_rdev_of(&metadata);
+        Ok(Metadata {
+            kind,
+            len: metadata.len(),
+            modified: metadata.modified()?,
+            mode: mode_of(&metadata),
+            rdev,
+            link_id: link_id_of(&metadata),
+        })
+    }
+
+    fn is_symlink(&self, path: &Path) -> io::Result<bool> {
+        Ok(fs::symlink_metadata(path)?.is_symlink())
+    }
+
+    #[cfg(unix)]
+    fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        // SAFETY: `path` is a valid C string and `stats` is only read after statvfs filled it
+        if unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let stats = unsafe { stats.assume_init() };
+        // Blocks available to unprivileged users, not the ones reserved for root
+        #[allow(clippy::unnecessary_cast)]
+        Ok(Some(stats.f_bavail as u64 * stats.f_frsize as u64))
+    }
+
+    fn is_case_insensitive(&self, path: &Path) -> io::Result<bool> {
+        // Look up an existing name with swapped case. If that resolves although no such entry
+        // was listed, the filesystem ignores case.
+        let mut names = HashSet::new();
+        for entry in fs::read_dir(path)? {
+            names.insert(entry?.file_name());
+        }
+        for name in names.iter().filter_map(|name| name.to_str()) {
+            let swapped = swap_case(name);
+            if swapped != name && !names.contains(OsStr::new(&swapped)) {
+                return Ok(fs::symlink_metadata(path.join(swapped)).is_ok());
+            }
+        }
+        // An empty directory can still be probed through its own name
+        if let (Some(parent), Some(name)) =
+            (path.parent(), path.file_name().and_then(OsStr::to_str))
+        {
+            let swapped = swap_case(name);
+            if swapped != name {
+                return Ok(fs::symlink_metadata(parent.join(swapped)).is_ok());
+            }
+        }
+        Ok(cfg!(any(windows, target_os = "macos")))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn write(&self, path: &Path, content: &mut dyn Read) -> io::Result<u64> {
+        let mut file = File::create(path)?;
+        io::copy(content, &mut file)
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn supports_hard_links(&self, path: &Path) -> io::Result<bool> {
+        // FAT and some network filesystems only fail once a link is actually created
+        let probe_path = path.join(HARD_LINK_PROBE_NAME);
+        let link_path = path.join(format!("{HARD_LINK_PROBE_NAME}-link"));
+        File::create(&probe_path)?;
+        let linked = fs::hard_link(&probe_path, &link_path);
+        let _ = fs::remove_file(&link_path);
+        fs::remove_file(&probe_path)?;
+        Ok(linked.is_ok())
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        fs::hard_link(original, link)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(path)
+    }
+
+    #[cfg(unix)]
+    fn create_special(&self, path: &Path, metadata: &Metadata) -> io::Result<()> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let permissions = (metadata.mode & 0o7777) as libc::mode_t;
+        // SAFETY: `c_path` is a valid C string
+        let result = match metadata.kind {
+            EntryKind::Fifo => unsafe { libc::mkfifo(c_path.as_ptr(), permissions) },
+            EntryKind::BlockDevice | EntryKind::CharDevice => {
+                let file_type = if metadata.kind == EntryKind::BlockDevice {
+                    libc::S_IFBLK
+                } else {
+                    libc::S_IFCHR
+                };
+                // Only allowed with enough privileges, usually for root
+                unsafe {
+                    libc::mknod(
+                        c_path.as_ptr(),
+                        file_type | permissions,
+                        metadata.rdev as libc::dev_t,
+                    )
+                }
+            }
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("can't create a {kind}"),
+                ))
+            }
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The umask may have cleared some of the permission bits
+        self.set_mode(path, metadata.mode)
+    }
+
+    #[cfg(unix)]
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+
+    #[cfg(not(unix))]
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let mut permissions = fs::metadata(path)?.permissions();
+        permissions.set_readonly(mode & 0o222 == 0);
+        fs::set_permissions(path, permissions)
+    }
+
+    fn probe_timestamp_granularity(&self, path: &Path) -> io::Result<Duration> {
+        // An odd number of seconds with every sub-second digit set reveals both sub-second
+        // truncation and rounding to two seconds
+        let probe_time = UNIX_EPOCH + Duration::new(1_000_000_001, 123_456_789);
+        let probe_path = path.join(GRANULARITY_PROBE_NAME);
+        let stored = File::create(&probe_path)
+            .and_then(|file| file.set_modified(probe_time))
+            .and_then(|_| fs::metadata(&probe_path)?.modified());
+        let removed = fs::remove_file(&probe_path);
+        let stored = stored?;
+        removed?;
+
+        let difference = match stored.duration_since(probe_time) {
+            Ok(difference) => difference,
+            Err(e) => e.duration(),
+        };
+        Ok(KNOWN_GRANULARITIES
+            .into_iter()
+            .find(|granularity| difference < *granularity)
+            .unwrap_or(difference))
+    }
+
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        // Directories cannot be opened for writing, but their timestamps can still be set
+        // through a read-only handle.
+        let file = if path.is_dir() {
+            File::open(path)?
+        } else {
+            File::options().write(true).open(path)?
+        };
+        file.set_modified(modified)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn test_probe_target_directory() {
+        let test_dir_path = env::current_dir().unwrap().join("test_dir_granularity");
+        let _ = fs::remove_dir_all(&test_dir_path);
+        fs::create_dir(&test_dir_path).unwrap();
+
+        // Every filesystem the tests run on has sub-second timestamps
+        let granularity = LocalStorage
+            .probe_timestamp_granularity(&test_dir_path)
+            .unwrap();
+        assert!(granularity < Duration::from_secs(1));
+        assert!(LocalStorage.supports_hard_links(&test_dir_path).unwrap());
+        assert!(LocalStorage.list(&test_dir_path).unwrap().is_empty());
+        #[cfg(unix)]
+        assert!(LocalStorage
+            .available_space(&test_dir_path)
+            .unwrap()
+            .is_some_and(|available| available > 0));
+
+        fs::remove_dir_all(&test_dir_path).unwrap();
+    }
+}
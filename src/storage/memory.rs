@@ -0,0 +1,439 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Read};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::{fold_name, EntryKind, Metadata, Storage};
+
+#[derive(Debug, Clone)]
+struct Node {
+    content: Option<Vec<u8>>, // None for directories
+    modified: SystemTime,
+    mode: u32,
+}
+
+/// Storage backend which keeps the whole tree in memory. Paths are used as given, so a tree
+/// rooted at `/source` has to be set up with `add_dir("/source")` first. Used to hold the
+/// content of archives and as a fast, isolated backend in tests.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryStorage {
+    nodes: RefCell<BTreeMap<PathBuf, Node>>,
+    /// Resolve paths like a case-insensitive filesystem does, keeping the case of the name an
+    /// entry was created with
+    case_insensitive: bool,
+}
+
+/// Stored paths directly inside `directory`. Paths sort by component, so everything below
+/// `directory` follows it without a gap and only that part of the map is visited.
+fn children<'a, V>(
+    nodes: &'a BTreeMap<PathBuf, V>,
+    directory: &'a Path,
+) -> impl Iterator<Item = &'a PathBuf> {
+    nodes
+        .range::<Path, _>((Bound::Excluded(directory), Bound::Unbounded))
+        .map(|(key, _)| key)
+        .take_while(move |key| key.starts_with(directory))
+        .filter(move |key| key.parent() == Some(directory))
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
+
+impl MemoryStorage {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a storage which behaves like a case-insensitive filesystem.
+    #[cfg(test)]
+    pub(crate) fn case_insensitive() -> Self {
+        MemoryStorage {
+            case_insensitive: true,
+            ..Self::default()
+        }
+    }
+
+    /// Return the stored path `path` refers to. On a case-insensitive storage that's the
+    /// existing entry whose name only differs in case, or a new name inside the resolved parent.
+    fn key(&self, path: &Path) -> PathBuf {
+        if !self.case_insensitive || self.nodes.borrow().contains_key(path) {
+            return path.to_path_buf();
+        }
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return path.to_path_buf();
+        };
+        let parent = self.key(parent);
+        let folded = fold_name(name);
+        let nodes = self.nodes.borrow();
+        let key = children(&nodes, &parent)
+            .find(|key| {
+                key.file_name()
+                    .is_some_and(|key_name| fold_name(key_name) == folded)
+            })
+            .cloned();
+        key.unwrap_or_else(|| parent.join(name))
+    }
+
+    /// Add a directory and all of its missing parents.
+    pub(crate) fn add_dir(&self, path: impl AsRef<Path>) {
+        let mut nodes = self.nodes.borrow_mut();
+        for ancestor in path.as_ref().ancestors() {
+            if ancestor.as_os_str().is_empty() || nodes.contains_key(ancestor) {
+                continue;
+            }
+            nodes.insert(
+                ancestor.to_path_buf(),
+                Node {
+                    content: None,
+                    modified: SystemTime::now(),
+                    mode: 0o755,
+                },
+            );
+        }
+    }
+
+    /// Add a file with the given content and modified timestamp, creating missing parents.
+    pub(crate) fn add_file(&self, path: impl AsRef<Path>, content: &[u8], modified: SystemTime) {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            self.add_dir(parent);
+        }
+        self.nodes.borrow_mut().insert(
+            path.to_path_buf(),
+            Node {
+                content: Some(content.to_vec()),
+                modified,
+                mode: 0o644,
+            },
+        );
+    }
+
+    /// Return the content of the file at `path`, or `None` if there is no such file.
+    #[cfg(test)]
+    pub(crate) fn content(&self, path: impl AsRef<Path>) -> Option<Vec<u8>> {
+        self.nodes
+            .borrow()
+            .get(path.as_ref())
+            .and_then(|node| node.content.clone())
+    }
+
+    fn require_parent_dir(&self, path: &Path) -> io::Result<()> {
+        let parent = path.parent().ok_or_else(|| not_found(path))?;
+        match self.nodes.borrow().get(&self.key(parent)) {
+            Some(node) if node.content.is_none() => Ok(()),
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} is not a directory", parent.display()),
+            )),
+            None => Err(not_found(parent)),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn list(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let path = &self.key(path);
+        let nodes = self.nodes.borrow();
+        match nodes.get(path) {
+            Some(node) if node.content.is_none() => {}
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotADirectory,
+                    format!("{} is not a directory", path.display()),
+                ))
+            }
+            None => return Err(not_found(path)),
+        }
+        Ok(children(&nodes, path).cloned().collect())
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let path = &self.key(path);
+        let nodes = self.nodes.borrow();
+        let node = nodes.get(path).ok_or_else(|| not_found(path))?;
+        Ok(match &node.content {
+            Some(content) => Metadata {
+                kind: EntryKind::File,
+                len: content.len() as u64,
+                modified: node.modified,
+                mode: node.mode,
+                rdev: 0,
+                link_id: None,
+            },
+            None => Metadata {
+                kind: EntryKind::Directory,
+                len: 0,
+                modified: node.modified,
+                mode: node.mode,
+                rdev: 0,
+                link_id: None,
+            },
+        })
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        let path = &self.key(path);
+        match self.nodes.borrow().get(path) {
+            Some(Node {
+                content: Some(content),
+                ..
+            }) => Ok(Box::new(Cursor::new(content.clone()))),
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::IsADirectory,
+                format!("{} is a directory", path.display()),
+            )),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn write(&self, path: &Path, content: &mut dyn Read) -> io::Result<u64> {
+        let path = &self.key(path);
+        self.require_parent_dir(path)?;
+        if self.metadata(path).is_ok_and(|metadata| metadata.is_dir()) {
+            return Err(io::Error::new(
+                io::ErrorKind::IsADirectory,
+                format!("{} is a directory", path.display()),
+            ));
+        }
+        let mut buffer = Vec::new();
+        let written = content.read_to_end(&mut buffer)? as u64;
+        let mut nodes = self.nodes.borrow_mut();
+        let mode = nodes.get(path).map_or(0o644, |node| node.mode);
+        nodes.insert(
+            path.to_path_buf(),
+            Node {
+                content: Some(buffer),
+                modified: SystemTime::now(),
+                mode,
+            },
+        );
+        Ok(written)
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let path = &self.key(path);
+        self.require_parent_dir(path)?;
+        let mut nodes = self.nodes.borrow_mut();
+        if nodes.contains_key(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            ));
+        }
+        nodes.insert(
+            path.to_path_buf(),
+            Node {
+                content: None,
+                modified: SystemTime::now(),
+                mode: 0o755,
+            },
+        );
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let path = &self.key(path);
+        let mut nodes = self.nodes.borrow_mut();
+        match nodes.get(path) {
+            Some(node) if node.content.is_some() => {
+                nodes.remove(path);
+                Ok(())
+            }
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::IsADirectory,
+                format!("{} is a directory", path.display()),
+            )),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let path = &self.key(path);
+        if !self.list(path)?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::DirectoryNotEmpty,
+                format!("{} is not empty", path.display()),
+            ));
+        }
+        self.nodes.borrow_mut().remove(path);
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        let path = &self.key(path);
+        if !self.metadata(path)?.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} is not a directory", path.display()),
+            ));
+        }
+        self.nodes
+            .borrow_mut()
+            .retain(|key, _| !key.starts_with(path));
+        Ok(())
+    }
+
+    fn is_case_insensitive(&self, _path: &Path) -> io::Result<bool> {
+        Ok(self.case_insensitive)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let path = &self.key(path);
+        let mut nodes = self.nodes.borrow_mut();
+        let node = nodes.get_mut(path).ok_or_else(|| not_found(path))?;
+        node.mode = mode;
+        Ok(())
+    }
+
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        let path = &self.key(path);
+        let mut nodes = self.nodes.borrow_mut();
+        let node = nodes.get_mut(path).ok_or_else(|| not_found(path))?;
+        node.modified = modified;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_memory_storage_round_trip() {
+        let storage = MemoryStorage::new();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        storage.add_dir("/root");
+        storage.add_file("/root/a/file.txt", b"content", modified);
+
+        assert_eq!(
+            storage.list(Path::new("/root")).unwrap(),
+            vec![PathBuf::from("/root/a")]
+        );
+        assert_eq!(
+            storage.metadata(Path::new("/root/a/file.txt")).unwrap(),
+            Metadata {
+                kind: EntryKind::File,
+                len: 7,
+                modified,
+                mode: 0o644,
+                rdev: 0,
+                link_id: None,
+            }
+        );
+
+        let mut content = Vec::new();
+        storage
+            .read(Path::new("/root/a/file.txt"))
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"content");
+
+        storage
+            .write(Path::new("/root/a/copy.txt"), &mut &b"copied"[..])
+            .unwrap();
+        assert_eq!(storage.content("/root/a/copy.txt").unwrap(), b"copied");
+    }
+
+    #[test]
+    fn test_memory_storage_list() {
+        let storage = MemoryStorage::new();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        storage.add_file("/root/a/b/deep.txt", b"", modified);
+        storage.add_file("/root/a/file.txt", b"", modified);
+        storage.add_file("/root/a.txt", b"", modified);
+        storage.add_dir("/root/ab");
+
+        // Siblings sharing the name as a prefix and nested entries are not listed
+        assert_eq!(
+            storage.list(Path::new("/root/a")).unwrap(),
+            vec![
+                PathBuf::from("/root/a/b"),
+                PathBuf::from("/root/a/file.txt")
+            ]
+        );
+        assert_eq!(
+            storage.list(Path::new("/root")).unwrap(),
+            vec![
+                PathBuf::from("/root/a"),
+                PathBuf::from("/root/a.txt"),
+                PathBuf::from("/root/ab"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_memory_storage_errors() {
+        let storage = MemoryStorage::new();
+        storage.add_dir("/root/existing");
+
+        // Missing parent, existing directory and removing a non-empty directory must fail the
+        // same way they do on a real filesystem.
+        assert_eq!(
+            storage
+                .create_dir(Path::new("/root/missing/inner"))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            storage
+                .create_dir(Path::new("/root/existing"))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(
+            storage.remove_dir(Path::new("/root")).unwrap_err().kind(),
+            io::ErrorKind::DirectoryNotEmpty
+        );
+        assert!(storage
+            .entry_kind(Path::new("/root/missing"))
+            .unwrap()
+            .is_none());
+
+        storage.remove_dir(Path::new("/root/existing")).unwrap();
+        assert!(storage
+            .entry_kind(Path::new("/root/existing"))
+            .unwrap()
+            .is_none());
+
+        storage.add_file("/root/full/inner/file.txt", b"", SystemTime::now());
+        storage.remove_dir_all(Path::new("/root/full")).unwrap();
+        assert_eq!(
+            storage.list(Path::new("/root")).unwrap(),
+            Vec::<PathBuf>::new()
+        );
+    }
+
+    #[test]
+    fn test_case_insensitive_memory_storage() {
+        let storage = MemoryStorage::case_insensitive();
+        storage.add_dir("/root/Dir");
+
+        // Writing through a differently cased path keeps the original names
+        storage
+            .write(Path::new("/root/DIR/File.txt"), &mut &b"first"[..])
+            .unwrap();
+        storage
+            .write(Path::new("/root/dir/FILE.TXT"), &mut &b"second"[..])
+            .unwrap();
+        assert_eq!(
+            storage.list(Path::new("/root/dir")).unwrap(),
+            vec![PathBuf::from("/root/Dir/File.txt")]
+        );
+        assert_eq!(storage.content("/root/Dir/File.txt").unwrap(), b"second");
+        assert!(storage.is_case_insensitive(Path::new("/root")).unwrap());
+        assert!(!MemoryStorage::new()
+            .is_case_insensitive(Path::new("/root"))
+            .unwrap());
+    }
+}
@@ -0,0 +1,282 @@
+mod archive;
+mod local;
+mod memory;
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+pub(crate) use archive::{ArchiveFormat, ArchiveStorage};
+pub(crate) use local::LocalStorage;
+pub(crate) use memory::MemoryStorage;
+use unicode_normalization::UnicodeNormalization;
+
+/// Kind of entry a path points to. Symlinks are always followed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum EntryKind {
+    File,
+    Directory,
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+}
+
+impl EntryKind {
+    /// Return whether the entry is neither a regular file nor a directory, so its content can't
+    /// be copied.
+    pub(crate) fn is_special(self) -> bool {
+        !matches!(self, EntryKind::File | EntryKind::Directory)
+    }
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryKind::File => write!(f, "file"),
+            EntryKind::Directory => write!(f, "directory"),
+            EntryKind::Fifo => write!(f, "FIFO"),
+            EntryKind::Socket => write!(f, "socket"),
+            EntryKind::BlockDevice => write!(f, "block device"),
+            EntryKind::CharDevice => write!(f, "character device"),
+        }
+    }
+}
+
+/// The subset of file metadata udir cares about, independent of the storage backend.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) struct Metadata {
+    pub(crate) kind: EntryKind,
+    pub(crate) len: u64,
+    pub(crate) modified: SystemTime,
+    /// Unix permission bits. Platforms which only know the read-only flag use 0o444 or 0o644.
+    pub(crate) mode: u32,
+    /// Device number of a device node, 0 for every other kind
+    pub(crate) rdev: u64,
+    /// Device and inode number of a file with more than one hard link, `None` otherwise
+    pub(crate) link_id: Option<(u64, u64)>,
+}
+
+impl Metadata {
+    pub(crate) fn is_dir(&self) -> bool {
+        self.kind == EntryKind::Directory
+    }
+}
+
+/// Form of a file name under which case-insensitive filesystems consider two names equal. Names
+/// are normalized to NFC as well, since `e` followed by a combining accent and `é` are the same
+/// name on some of them.
+pub(crate) fn fold_name(name: &OsStr) -> String {
+    name.to_string_lossy()
+        .nfc()
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// A place files and directories can be read from and written to. All paths passed to the
+/// backend are full paths, exactly as they were given to udir or returned by `list`.
+pub(crate) trait Storage {
+    /// Return the paths of all entries directly inside the `path` directory.
+    fn list(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Return the metadata of the entry at `path`.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Return the kind of the entry at `path`, or `None` if nothing exists there.
+    fn entry_kind(&self, path: &Path) -> io::Result<Option<EntryKind>> {
+        match self.metadata(path) {
+            Ok(metadata) => Ok(Some(metadata.kind)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Return whether `path` itself is a symbolic link. All other methods follow symlinks.
+    fn is_symlink(&self, _path: &Path) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Open the file at `path` for reading.
+    fn read(&self, path: &Path) -> io::Result<Box<dyn Read + '_>>;
+
+    /// Create or truncate the file at `path` and fill it with everything from `content`.
+    /// Returns the number of bytes written.
+    fn write(&self, path: &Path, content: &mut dyn Read) -> io::Result<u64>;
+
+    /// Create a single directory. The parent has to exist already.
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove a file.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove an empty directory.
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove a directory with all of its content. Symlinks inside are removed, not followed.
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Create a FIFO or device node at `path` like the one described by `metadata`, including
+    /// its permission bits.
+    fn create_special(&self, _path: &Path, metadata: &Metadata) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("can't create a {} here", metadata.kind),
+        ))
+    }
+
+    /// Return whether hard links can be created inside the `path` directory.
+    fn supports_hard_links(&self, _path: &Path) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Create `link` as another name for the existing file `original`.
+    fn hard_link(&self, _original: &Path, _link: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "hard links are not supported here",
+        ))
+    }
+
+    /// Set the permission bits of the entry at `path`.
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()>;
+
+    /// Set the last modified timestamp of the entry at `path`.
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()>;
+
+    /// Return the number of bytes which can still be written below the `path` directory, or
+    /// `None` if the backend can't tell.
+    fn available_space(&self, _path: &Path) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Return whether names inside the `path` directory are compared case-insensitively, so
+    /// that names which only differ in case refer to the same entry.
+    fn is_case_insensitive(&self, _path: &Path) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Smallest difference between two last modified timestamps the backend can store.
+    /// Timestamps are compared at this precision, so a copy never looks older than its source.
+    fn timestamp_granularity(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+
+    /// Find out which timestamp granularity the `path` directory actually has. That can be
+    /// coarser than `timestamp_granularity`, e.g. on a FAT formatted drive.
+    fn probe_timestamp_granularity(&self, _path: &Path) -> io::Result<Duration> {
+        Ok(self.timestamp_granularity())
+    }
+}
+
+/// A SOURCE or TARGET given on the command line, either a local directory or an archive file.
+pub(crate) enum Endpoint {
+    Local(LocalStorage),
+    Archive(ArchiveStorage),
+}
+
+impl Endpoint {
+    /// Open `path` as an archive if it has a known archive extension, or as a local directory
+    /// otherwise. A missing archive is treated as empty so that it can be created as a target.
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        match ArchiveFormat::from_path(path) {
+            Some(format) => Ok(Endpoint::Archive(ArchiveStorage::open(path, format)?)),
+            None => Ok(Endpoint::Local(LocalStorage)),
+        }
+    }
+
+    /// Persist everything written to the endpoint. Returns whether anything had to be written.
+    pub(crate) fn finish(&self) -> io::Result<bool> {
+        match self {
+            Endpoint::Local(_) => Ok(false),
+            Endpoint::Archive(archive) => archive.save(),
+        }
+    }
+
+    fn storage(&self) -> &dyn Storage {
+        match self {
+            Endpoint::Local(local) => local,
+            Endpoint::Archive(archive) => archive,
+        }
+    }
+}
+
+impl Storage for Endpoint {
+    fn list(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.storage().list(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.storage().metadata(path)
+    }
+
+    fn entry_kind(&self, path: &Path) -> io::Result<Option<EntryKind>> {
+        self.storage().entry_kind(path)
+    }
+
+    fn is_symlink(&self, path: &Path) -> io::Result<bool> {
+        self.storage().is_symlink(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        self.storage().read(path)
+    }
+
+    fn write(&self, path: &Path, content: &mut dyn Read) -> io::Result<u64> {
+        self.storage().write(path, content)
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.storage().create_dir(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.storage().remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.storage().remove_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.storage().remove_dir_all(path)
+    }
+
+    fn create_special(&self, path: &Path, metadata: &Metadata) -> io::Result<()> {
+        self.storage().create_special(path, metadata)
+    }
+
+    fn supports_hard_links(&self, path: &Path) -> io::Result<bool> {
+        self.storage().supports_hard_links(path)
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        self.storage().hard_link(original, link)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.storage().set_mode(path, mode)
+    }
+
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        self.storage().set_modified(path, modified)
+    }
+
+    fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        self.storage().available_space(path)
+    }
+
+    fn is_case_insensitive(&self, path: &Path) -> io::Result<bool> {
+        self.storage().is_case_insensitive(path)
+    }
+
+    fn timestamp_granularity(&self) -> Duration {
+        self.storage().timestamp_granularity()
+    }
+
+    fn probe_timestamp_granularity(&self, path: &Path) -> io::Result<Duration> {
+        self.storage().probe_timestamp_granularity(path)
+    }
+}
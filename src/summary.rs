@@ -0,0 +1,92 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::progress::{format_bytes, format_duration};
+
+/// What a run did, printed at its end.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct RunSummary {
+    pub(crate) scanned_files: usize,
+    pub(crate) unchanged_files: usize,
+    pub(crate) new_files: usize,
+    pub(crate) updated_files: usize,
+    /// Number of files which weren't copied although they differ, by reason
+    pub(crate) skipped: Vec<(String, usize)>,
+    pub(crate) created_directories: usize,
+    pub(crate) copied_bytes: u64,
+    pub(crate) failures: usize,
+    pub(crate) elapsed: Duration,
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Summary:")?;
+        writeln!(f, "    Files scanned: {}", self.scanned_files)?;
+        writeln!(f, "    Unchanged: {}", self.unchanged_files)?;
+        writeln!(f, "    New: {}", self.new_files)?;
+        writeln!(f, "    Updated: {}", self.updated_files)?;
+        let skipped: usize = self.skipped.iter().map(|(_, count)| count).sum();
+        if skipped == 0 {
+            writeln!(f, "    Skipped: 0")?;
+        } else {
+            let reasons: Vec<_> = self
+                .skipped
+                .iter()
+                .filter(|(_, count)| *count > 0)
+                .map(|(reason, count)| format!("{reason}: {count}"))
+                .collect();
+            writeln!(f, "    Skipped: {skipped} ({})", reasons.join(", "))?;
+        }
+        writeln!(f, "    Directories created: {}", self.created_directories)?;
+        write!(
+            f,
+            "    Copied: {} in {}",
+            format_bytes(self.copied_bytes),
+            format_duration(self.elapsed)
+        )?;
+        let seconds = self.elapsed.as_secs_f64();
+        if self.copied_bytes > 0 && seconds > 0. {
+            write!(
+                f,
+                " ({}/s)",
+                format_bytes((self.copied_bytes as f64 / seconds) as u64)
+            )?;
+        }
+        writeln!(f)?;
+        write!(f, "    Failures: {}", self.failures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_run_summary() {
+        let summary = RunSummary {
+            scanned_files: 10,
+            unchanged_files: 4,
+            new_files: 2,
+            updated_files: 1,
+            skipped: vec![
+                ("--min-size".to_string(), 2),
+                ("type conflicts".to_string(), 0),
+                ("declined".to_string(), 1),
+            ],
+            created_directories: 3,
+            copied_bytes: 4096,
+            failures: 0,
+            elapsed: Duration::from_secs(2),
+        };
+        assert_eq!(
+            summary.to_string(),
+            "Summary:\n    Files scanned: 10\n    Unchanged: 4\n    New: 2\n    Updated: 1\n    \
+             Skipped: 3 (--min-size: 2, declined: 1)\n    Directories created: 3\n    \
+             Copied: 4.00 KiB in 00:02 (2.00 KiB/s)\n    Failures: 0"
+        );
+
+        assert!(RunSummary::default()
+            .to_string()
+            .contains("Skipped: 0\n    Directories created: 0\n    Copied: 0 B in 00:00\n"));
+    }
+}